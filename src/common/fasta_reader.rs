@@ -0,0 +1,220 @@
+// Streaming FASTA reader shared by the sequence programs. It is included with
+// `#[path = "common/fasta_reader.rs"] mod fasta_reader;`, as every file
+// directly in `src` is a program.
+
+#![allow(dead_code)]
+
+use std::io::{self, BufRead};
+
+/// One record of a FASTA file.
+pub struct Record {
+    /// The header line, without the leading `>` and the line ending.
+    pub header: Vec<u8>,
+    /// The sequence, without line endings.
+    pub seq: Vec<u8>,
+    /// Length of the first sequence line, or 0 if the sequence is empty.
+    pub line_width: usize,
+}
+
+impl Record {
+    /// The identifier of the record: the header up to the first whitespace.
+    pub fn id(&self) -> &[u8] {
+        id(&self.header)
+    }
+}
+
+/// The identifier in a header: the header up to the first whitespace.
+pub fn id(header: &[u8]) -> &[u8] {
+    header.split(|c| c.is_ascii_whitespace()).next().unwrap_or(&[])
+}
+
+/// Reads the records of a FASTA or multi-FASTA file one at a time, or their
+/// sequences piece by piece. Line endings may be `\n` or `\r\n`, the last
+/// line may have no line ending, and blank lines are ignored.
+pub struct Reader<R> {
+    input: R,
+    line: Vec<u8>,
+    line_number: usize,
+    /// The header of the next record, when it has already been read.
+    next_header: Option<Vec<u8>>,
+    /// Whether the next lines are the sequence of the current record.
+    in_record: bool,
+    /// Length of the first sequence line of the current record so far.
+    line_width: usize,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(input: R) -> Reader<R> {
+        Reader {
+            input: input,
+            line: Vec::with_capacity(128),
+            line_number: 0,
+            next_header: None,
+            in_record: false,
+            line_width: 0,
+        }
+    }
+
+    /// Read the next line into `self.line`, without its line ending. Returns
+    /// false at the end of the input.
+    fn read_line(&mut self) -> io::Result<bool> {
+        self.line.clear();
+        if self.input.read_until(b'\n', &mut self.line)? == 0 {
+            return Ok(false);
+        }
+        self.line_number += 1;
+        if self.line.last() == Some(&b'\n') {
+            self.line.pop();
+        }
+        if self.line.last() == Some(&b'\r') {
+            self.line.pop();
+        }
+        Ok(true)
+    }
+
+    /// Skip to the next record and return its header, or `None` at the end
+    /// of the input.
+    pub fn next_header(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.line_width = 0;
+        if let Some(header) = self.next_header.take() {
+            self.in_record = true;
+            return Ok(Some(header));
+        }
+        while self.read_line()? {
+            if self.line.starts_with(b">") {
+                self.in_record = true;
+                return Ok(Some(self.line[1..].to_vec()));
+            }
+            if !self.in_record && !self.line.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: sequence data before the first header", self.line_number)));
+            }
+        }
+        self.in_record = false;
+        Ok(None)
+    }
+
+    /// Append the sequence of the current record to `seq`, a line at a time,
+    /// until `seq` holds at least `limit` bytes. Returns false once the
+    /// sequence has ended.
+    pub fn read_seq(&mut self, seq: &mut Vec<u8>, limit: usize) -> io::Result<bool> {
+        while self.in_record && seq.len() < limit {
+            if !self.read_line()? {
+                self.in_record = false;
+            } else if self.line.starts_with(b">") {
+                self.next_header = Some(self.line[1..].to_vec());
+                self.in_record = false;
+            } else {
+                if self.line_width == 0 {
+                    self.line_width = self.line.len();
+                }
+                seq.extend_from_slice(&self.line);
+            }
+        }
+        Ok(self.in_record)
+    }
+
+    /// Append the rest of the sequence of the current record to `seq` as it
+    /// is laid out in the input, every line ending with `\n`: `\r\n` line
+    /// endings are converted, blank lines dropped, a missing final line
+    /// ending added, and lines re-wrapped to the width of the first if their
+    /// widths differ. This reads the record in one go, which is faster than
+    /// `read_seq` for large records, but doesn't count its lines: line
+    /// numbers in errors are only right before the first record. Returns
+    /// the length of the first line, or 0 if the sequence is empty.
+    pub fn read_seq_lines(&mut self, seq: &mut Vec<u8>) -> io::Result<usize> {
+        if !self.in_record {
+            return Ok(self.line_width);
+        }
+        self.in_record = false;
+        let start = seq.len();
+        self.input.read_until(b'>', seq)?;
+        if seq.last() == Some(&b'>') {
+            seq.pop();
+            // The rest of the line is the next header.
+            self.line.clear();
+            self.input.read_until(b'\n', &mut self.line)?;
+            let header_len = self.line.len() - (self.line.last() == Some(&b'\n')) as usize;
+            let header_len = header_len - (self.line[..header_len].last() == Some(&b'\r')) as usize;
+            self.next_header = Some(self.line[..header_len].to_vec());
+        }
+
+        // Only rewrite the lines if some need it: all lines but the last must
+        // have the width of the first, so the line endings fall at that
+        // stride and there are no others.
+        let lines = &seq[start..];
+        let width = lines.iter().position(|&c| c == b'\n').unwrap_or(0);
+        let clean = lines.is_empty()
+            || width > 0
+            && lines.last() == Some(&b'\n')
+            && !lines.ends_with(b"\n\n")
+            && !lines.contains(&b'\r')
+            && lines[..lines.len() - 1].iter().skip(width).step_by(width + 1).all(|&c| c == b'\n')
+            && lines.iter().filter(|&&c| c == b'\n').count() == (lines.len() + width) / (width + 1);
+        if !clean {
+            // Re-wrap the bases to the width of the first non-blank line.
+            let lines = seq.split_off(start);
+            let width = lines.split(|&c| c == b'\n')
+                .map(|line| line.iter().filter(|&&c| c != b'\r').count())
+                .find(|&width| width > 0)
+                .unwrap_or(1);
+            let bases: Vec<u8> = lines.into_iter().filter(|&c| c != b'\n' && c != b'\r').collect();
+            for line in bases.chunks(width) {
+                seq.extend_from_slice(line);
+                seq.push(b'\n');
+            }
+        }
+        self.line_width = seq[start..].iter().position(|&c| c == b'\n').unwrap_or(0);
+        Ok(self.line_width)
+    }
+
+    /// Read the rest of the record with the header `header`.
+    fn read_record(&mut self, header: Option<Vec<u8>>) -> io::Result<Option<Record>> {
+        let header = match header {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let mut seq = Vec::with_capacity(64 * 1024);
+        self.read_seq(&mut seq, usize::max_value())?;
+        Ok(Some(Record {
+            header: header,
+            seq: seq,
+            line_width: self.line_width,
+        }))
+    }
+
+    /// Read the next record, or return `None` at the end of the input.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let header = self.next_header()?;
+        self.read_record(header)
+    }
+
+    /// Skip to the first record with the identifier `id`, and return its
+    /// header. Its sequence can then be read with `read_seq`.
+    pub fn find_header(&mut self, id: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        while let Some(header) = self.next_header()? {
+            if self::id(&header) == id {
+                return Ok(Some(header));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Skip to the first record with the identifier `id`, and read it.
+    pub fn find(&mut self, id: &[u8]) -> io::Result<Option<Record>> {
+        let header = self.find_header(id)?;
+        self.read_record(header)
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        match self.next_record() {
+            Ok(record) => record.map(Ok),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
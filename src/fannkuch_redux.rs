@@ -11,8 +11,141 @@ extern crate rayon;
 use std::cmp;
 use rayon::prelude::*;
 
-pub fn pack(perm: &[u8; 16]) -> u64 {
-    perm.iter().rev().fold(0, |acc, &i| (acc << 4) + i as u64)
+/// Largest supported `n`: 20! is the largest factorial fitting in a `u64`.
+pub const MAX_N: usize = 20;
+
+/// A permutation of `0..n` packed for fast flips and rotations.
+pub trait Perm: Copy {
+    fn pack(perm: &[u8; MAX_N]) -> Self;
+    fn first(self) -> u8;
+    fn flips(self) -> i32;
+    fn rotate(self, n: usize) -> Self;
+    /// Call `f`, compiled with the CPU features this representation needs.
+    fn run<R, F: FnOnce() -> R>(f: F) -> R { f() }
+}
+
+/// Up to 16 elements, one per nibble.
+impl Perm for u64 {
+    fn pack(perm: &[u8; MAX_N]) -> u64 { pack(perm) }
+    fn first(self) -> u8 { (self & 0xf) as u8 }
+    fn flips(self) -> i32 { flips(self) }
+    fn rotate(self, n: usize) -> u64 { rotate(self, n) }
+}
+
+/// Up to `MAX_N` elements, one per byte.
+#[derive(Clone, Copy)]
+pub struct Wide([u8; MAX_N]);
+impl Perm for Wide {
+    fn pack(perm: &[u8; MAX_N]) -> Wide { Wide(*perm) }
+    fn first(self) -> u8 { self.0[0] }
+    fn flips(self) -> i32 {
+        let (mut flip, mut flip_count) = (self.0, 0);
+        loop {
+            let flip_index = flip[0] as usize;
+            if flip_index == 0 { break; }
+            flip[..=flip_index].reverse();
+            flip_count += 1;
+        }
+        flip_count
+    }
+    fn rotate(mut self, n: usize) -> Wide {
+        self.0[..=n].rotate_left(1);
+        self
+    }
+}
+
+/// Up to 16 elements in an SSE register, where each prefix reversal and
+/// rotation is a single byte shuffle (pshufb) from a precomputed table. Only
+/// use it after checking `is_x86_feature_detected!("ssse3")`.
+#[cfg(target_arch = "x86_64")]
+mod simd {
+    use std::arch::x86_64::*;
+    use super::{Perm, MAX_N};
+
+    /// `FLIP[k]` reverses the first `k + 1` bytes.
+    static FLIP: [[u8; 16]; 16] = shuffles(true);
+    /// `ROTATE[k]` rotates the first `k + 1` bytes left by one.
+    static ROTATE: [[u8; 16]; 16] = shuffles(false);
+
+    const fn shuffles(flip: bool) -> [[u8; 16]; 16] {
+        let mut table = [[0; 16]; 16];
+        let mut k = 0;
+        while k < 16 {
+            let mut i = 0;
+            while i < 16 {
+                table[k][i] = if i > k { i } else if flip { k - i } else { (i + 1) % (k + 1) } as u8;
+                i += 1;
+            }
+            k += 1;
+        }
+        table
+    }
+
+    #[inline(always)]
+    fn shuffle(perm: __m128i, table: &[u8; 16]) -> __m128i {
+        unsafe { _mm_shuffle_epi8(perm, _mm_loadu_si128(table.as_ptr() as *const __m128i)) }
+    }
+
+    #[derive(Clone, Copy)]
+    pub struct Simd(__m128i);
+    impl Perm for Simd {
+        #[inline(always)]
+        fn pack(perm: &[u8; MAX_N]) -> Simd {
+            Simd(unsafe { _mm_loadu_si128(perm.as_ptr() as *const __m128i) })
+        }
+        #[inline(always)]
+        fn first(self) -> u8 {
+            unsafe { _mm_cvtsi128_si32(self.0) as u8 }
+        }
+        #[inline(always)]
+        fn flips(self) -> i32 {
+            let (mut flip, mut flip_count) = (self, 0);
+            loop {
+                let flip_index = flip.first() as usize;
+                if flip_index == 0 { break; }
+                flip.0 = shuffle(flip.0, &FLIP[flip_index]);
+                flip_count += 1;
+            }
+            flip_count
+        }
+        #[inline(always)]
+        fn rotate(self, n: usize) -> Simd {
+            Simd(shuffle(self.0, &ROTATE[n]))
+        }
+        fn run<R, F: FnOnce() -> R>(f: F) -> R {
+            #[target_feature(enable = "ssse3")]
+            unsafe fn ssse3<R, F: FnOnce() -> R>(f: F) -> R { f() }
+            unsafe { ssse3(f) }
+        }
+    }
+}
+
+/// Check the representation `P` against the nibble implementation over every
+/// permutation of `n` elements, in the order `permute` generates them.
+pub fn check<P: Perm>(n: usize) -> Result<(), String> {
+    P::run(|| {
+        let mut current = [0u8; MAX_N];
+        current.iter_mut().enumerate().for_each(|(i, value)| *value = i as u8);
+        let (mut nibbles, mut perm) = (u64::pack(&current), P::pack(&current));
+        let (mut count1, mut count2) = ([0u8; MAX_N], [0u8; MAX_N]);
+        let perm_max = (1..=n as u64).product::<u64>();
+        for index in 0..perm_max {
+            let (expected, actual) = (nibbles.flips(), perm.flips());
+            if nibbles.first() != perm.first() || expected != actual {
+                return Err(format!("n = {}, permutation {}: {} flips instead of {}",
+                                   n, index, actual, expected));
+            }
+            if index + 1 < perm_max {
+                nibbles = permute(nibbles, &mut count1);
+                perm = permute(perm, &mut count2);
+            }
+        }
+        Ok(())
+    })
+}
+
+pub fn pack(perm: &[u8; MAX_N]) -> u64 {
+    perm[..16].iter().rev().fold(0, |acc, &i| (acc << 4) + i as u64)
 }
 pub fn flips(perm: u64) -> i32 {
     const LOWER: u64 = 0x0f0f0f0f0f0f0f0fu64;
@@ -27,14 +160,14 @@ pub fn flips(perm: u64) -> i32 {
     }
     flip_count
 }
-pub fn permute(perm: u64, count: &mut [u8; 16]) -> u64 {
-    let mut perm = rotate(perm, 1);
+pub fn permute<P: Perm>(perm: P, count: &mut [u8; MAX_N]) -> P {
+    let mut perm = perm.rotate(1);
     // Generate the next permutation.
     let mut i = 1;
     while count[i] >= i as u8 {
         count[i] = 0;
         i += 1;
-        perm = rotate(perm, i);
+        perm = perm.rotate(i);
     }
     count[i] += 1;
     perm
@@ -45,92 +178,387 @@ fn rotate(perm: u64, n: usize) -> u64 {
     perm & mask | (perm & !mask) >> 4 | (perm & 0xf) << n4
 }
 
-// This value controls the preferred maximum number of  blocks the workload is
-// broken up into. The actual value may be one higher (if the number of
-// permutations doesn't divide exactly by this value) or might be set to 1 if
-// the number of permutations is lower than this value.
-const NUM_BLOCKS: u32 = 24;
-
-fn fannkuch(n: i32) -> (i32, i32) {
-    // Precompute a table a factorials to reuse all over the place.
-    let mut factorials = [1; 16];
-    for i in 1..=n as usize {
-        factorials[i] = factorials[i - 1] * i as u32;
-    }
-    let perm_max = factorials[n as usize];
-
-    // Compute the number of blocks and their size. If n! is less than
-    // NUM_BLOCKS then use a single block (perform the work serially for small
-    // values of n). If n! doesn't divide exactly by NUM_BLOCKS, then add one
-    // extra block to compute the remainder.
-    let (num_blocks, block_size) = if perm_max < NUM_BLOCKS {
-        (1, perm_max)
-    } else {
-        (NUM_BLOCKS + if perm_max % NUM_BLOCKS == 0 { 0 } else { 1 },
-         perm_max / NUM_BLOCKS)
-    };
-
-    // Compute the `checksum` and `maxflips` for each block in parallel.
-    (0..num_blocks).into_par_iter().map(|i_block| {
-        let initial = i_block * block_size;
-        let mut count = [0u8; 16];
-        let mut temp = [0u8; 16];
-        let mut current = [0u8; 16];
-
-        // Initialise `count` and the current permutation (`current`)
-        current.iter_mut().enumerate().for_each(|(i, value)| *value = i as u8);
+// Blocks of permutations are split in halves on demand, as `rayon` threads
+// run out of work, but not below this size so that decoding the first
+// permutation of a block stays negligible.
+const MIN_BLOCK_SIZE: u64 = 1024;
 
-        let mut permutation_index = initial as i32;
-        for i in (1..n as usize).rev() {
-            let factorial = factorials[i] as i32;
-            let d = permutation_index / factorial;
-            permutation_index %= factorial;
-            count[i] = d as u8;
+/// What is computed from the flip counts of the permutations. Each block
+/// accumulates its own, and they are then merged in order by `rayon`.
+trait Tally: Default + Send {
+    fn add(&mut self, permutation_index: u64, flip_count: i32);
+    fn merge(self, other: Self) -> Self;
+}
 
-            temp.copy_from_slice(&current);
-            let d = d as usize;
-            current[0..=i - d].copy_from_slice(&temp[d..=i]);
-            current[i - d + 1..=i].copy_from_slice(&temp[0..d])
+/// The benchmark output.
+#[derive(Default)]
+struct Summary {
+    checksum: i64,
+    maxflips: i32,
+}
+impl Tally for Summary {
+    #[inline(always)]
+    fn add(&mut self, permutation_index: u64, flip_count: i32) {
+        self.checksum += if permutation_index % 2 == 0 {
+            flip_count as i64
+        } else {
+            -flip_count as i64
+        };
+        self.maxflips = cmp::max(self.maxflips, flip_count);
+    }
+    fn merge(self, other: Summary) -> Summary {
+        Summary {
+            checksum: self.checksum + other.checksum,
+            maxflips: cmp::max(self.maxflips, other.maxflips),
+        }
+    }
+}
+
+/// The benchmark output, along with the number of permutations needing each
+/// number of flips and the indices of those needing `maxflips`.
+#[derive(Default)]
+struct Stats {
+    summary: Summary,
+    histogram: Vec<u64>,
+    worst: Vec<u64>,
+}
+impl Tally for Stats {
+    fn add(&mut self, permutation_index: u64, flip_count: i32) {
+        if flip_count > self.summary.maxflips {
+            self.worst.clear();
+        }
+        if flip_count >= self.summary.maxflips {
+            self.worst.push(permutation_index);
+        }
+        self.summary.add(permutation_index, flip_count);
+        let flip_count = flip_count as usize;
+        if self.histogram.len() <= flip_count {
+            self.histogram.resize(flip_count + 1, 0);
         }
+        self.histogram[flip_count] += 1;
+    }
+    fn merge(mut self, mut other: Stats) -> Stats {
+        if self.histogram.len() < other.histogram.len() {
+            self.histogram.resize(other.histogram.len(), 0);
+        }
+        for (a, b) in self.histogram.iter_mut().zip(&other.histogram) {
+            *a += *b;
+        }
+        if other.summary.maxflips > self.summary.maxflips {
+            self.worst = other.worst;
+        } else if other.summary.maxflips == self.summary.maxflips {
+            self.worst.append(&mut other.worst);
+        }
+        self.summary = self.summary.merge(other.summary);
+        self
+    }
+}
+
+/// Compute the flip counts of the permutations of `n` elements. If `simd` is
+/// set and the CPU supports it, use the SSE representation: it is slower than
+/// nibbles on the CPUs we measured, as each flip has to move the first element
+/// back to a general purpose register.
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+fn fannkuch<T: Tally>(n: usize, simd: bool) -> T {
+    assert!(n >= 1 && n <= MAX_N, "n must be between 1 and {}", MAX_N);
+    // Nibbles and SSE registers are faster, but can only hold 16 elements.
+    if n > 16 {
+        return fannkuch_with::<Wide, T>(n);
+    }
+    #[cfg(target_arch = "x86_64")]
+    {
+        if simd && is_x86_feature_detected!("ssse3") {
+            return fannkuch_with::<simd::Simd, T>(n);
+        }
+    }
+    fannkuch_with::<u64, T>(n)
+}
+
+/// Precompute a table a factorials to reuse all over the place.
+fn factorials(n: usize) -> [u64; MAX_N + 1] {
+    let mut factorials = [1; MAX_N + 1];
+    for i in 1..=n {
+        factorials[i] = factorials[i - 1] * i as u64;
+    }
+    factorials
+}
+
+/// Compute the permutation number `permutation_index` of `n` elements, in the
+/// order they are generated by `permute`, along with the matching `count`.
+fn decode(n: usize, factorials: &[u64; MAX_N + 1], mut permutation_index: u64)
+          -> ([u8; MAX_N], [u8; MAX_N]) {
+    let mut count = [0u8; MAX_N];
+    let mut temp = [0u8; MAX_N];
+    let mut current = [0u8; MAX_N];
+
+    // Initialise `count` and the current permutation (`current`)
+    current.iter_mut().enumerate().for_each(|(i, value)| *value = i as u8);
+
+    for i in (1..n).rev() {
+        let factorial = factorials[i];
+        let d = permutation_index / factorial;
+        permutation_index %= factorial;
+        count[i] = d as u8;
+
+        temp.copy_from_slice(&current);
+        let d = d as usize;
+        current[0..=i - d].copy_from_slice(&temp[d..=i]);
+        current[i - d + 1..=i].copy_from_slice(&temp[0..d])
+    }
+    (current, count)
+}
+
+/// The permutation number `index` of `0..n`, in the order of `permute`.
+pub fn unrank(n: usize, index: u64) -> Vec<u8> {
+    assert!(n >= 1 && n <= MAX_N, "n must be between 1 and {}", MAX_N);
+    let factorials = factorials(n);
+    assert!(index < factorials[n], "index out of range");
+    decode(n, &factorials, index).0[..n].to_vec()
+}
+
+/// The index of a permutation of `0..perm.len()`, in the order of `permute`.
+pub fn rank(perm: &[u8]) -> u64 {
+    let n = perm.len();
+    assert!(n >= 1 && n <= MAX_N, "n must be between 1 and {}", MAX_N);
+    let factorials = factorials(n);
+
+    // Replay `decode`: the element at `i` doesn't move after the prefix up to
+    // `i` has been rotated, which gives the digit `d` of that rotation.
+    let mut current: Vec<u8> = (0..n as u8).collect();
+    let mut index = 0;
+    for i in (1..n).rev() {
+        let k = current[..=i].iter().position(|&x| x == perm[i])
+            .expect("not a permutation");
+        let d = (k + 1) % (i + 1);
+        current[..=i].rotate_left(d);
+        index += d as u64 * factorials[i];
+    }
+    assert!(current[0] == perm[0], "not a permutation");
+    index
+}
+
+/// Iterator over the permutations of `0..n` with their index in a range, in
+/// the order of `permute`.
+pub struct Permutations {
+    n: usize,
+    perm: Wide,
+    count: [u8; MAX_N],
+    index: u64,
+    end: u64,
+}
+impl Iterator for Permutations {
+    type Item = Vec<u8>;
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if self.index >= self.end {
+            return None;
+        }
+        let res = self.perm.0[..self.n].to_vec();
+        self.index += 1;
+        if self.index < self.end {
+            self.perm = permute(self.perm, &mut self.count);
+        }
+        Some(res)
+    }
+}
+
+pub fn permutations(n: usize, range: std::ops::Range<u64>) -> Permutations {
+    assert!(n >= 1 && n <= MAX_N, "n must be between 1 and {}", MAX_N);
+    let factorials = factorials(n);
+    let end = cmp::min(range.end, factorials[n]);
+    let start = cmp::min(range.start, end);
+    let (current, count) = decode(n, &factorials, if start < end { start } else { 0 });
+    Permutations { n: n, perm: Wide::pack(&current), count: count, index: start, end: end }
+}
+
+fn fannkuch_with<P: Perm, T: Tally>(n: usize) -> T {
+    let factorials = factorials(n);
+    let perm_max = factorials[n];
+
+    // Split the permutations into blocks `initial..end`, adapting to the
+    // number of threads and their load: `rayon` only asks for a block to be
+    // split when another thread is idle.
+    let blocks = rayon::iter::split((0, perm_max), |(initial, end)| {
+        if end - initial < 2 * MIN_BLOCK_SIZE {
+            ((initial, end), None)
+        } else {
+            let mid = initial + (end - initial) / 2;
+            ((initial, mid), Some((mid, end)))
+        }
+    });
+
+    // Compute the tally for each block in parallel.
+    blocks.map(|(initial, end)| P::run(|| {
+        let (current, mut count) = decode(n, &factorials, initial);
 
         // Iterate over each permutation in the block.
-        let mut perm = pack(&current);
-        let last_permutation_in_block = cmp::min(initial + block_size,
-                                                 perm_max) - 1;
+        let mut perm = P::pack(&current);
+        let last_permutation_in_block = end - 1;
         let mut permutation_index = initial;
-        let (mut checksum, mut maxflips) = (0, 0);
+        let mut tally = T::default();
         loop {
             // If the first value in the current permutation is not 1 (0) then
             // we will need to do at least one flip for `current`.
-            if perm & 0xf > 0 {
-                let flip_count = flips(perm);
-                // Update the `checksum` and `maxflips` of this block.
-                checksum += if permutation_index % 2 == 0 {
-                    flip_count
-                } else {
-                    -flip_count
-                };
-                maxflips = cmp::max(maxflips, flip_count);
-            }
+            let flip_count = if perm.first() > 0 { perm.flips() } else { 0 };
+            tally.add(permutation_index, flip_count);
 
             // If this was the last permutation in the block, we're done: return
-            // the `checksum` and `maxflips` values which get reduced across
-            // blocks in parallel by `rayon`.
+            // the tally, which get reduced across blocks in parallel by
+            // `rayon`.
             if permutation_index >= last_permutation_in_block {
-                return (checksum, maxflips);
+                return tally;
             }
             permutation_index += 1;
             perm = permute(perm, &mut count);
         }
-    }).reduce(|| (0, 0),
-              |(cs1, mf1), (cs2, mf2)| (cs1 + cs2, cmp::max(mf1, mf2)))
+    })).reduce(T::default, T::merge)
 }
 
 fn main() {
-    let n = std::env::args().nth(1)
-        .and_then(|n| n.parse().ok())
-        .unwrap_or(7);
+    let (mut n, mut simd, mut stats) = (7, false, false);
+    for arg in std::env::args().skip(1) {
+        match &*arg {
+            "--simd" => simd = true,
+            "--stats" => stats = true,
+            "--check-simd" => return check_simd(),
+            "--check-wide" => return check_wide(),
+            "--check-rank" => return check_rank(),
+            _ => n = arg.parse().unwrap_or_else(|_| panic!("unknown argument {}", arg)),
+        }
+    }
+
+    if !stats {
+        let Summary { checksum, maxflips } = fannkuch(n, simd);
+        println!("{}\nPfannkuchen({}) = {}", checksum, n, maxflips);
+        return;
+    }
 
-    let (checksum, maxflips) = fannkuch(n);
-    println!("{}\nPfannkuchen({}) = {}", checksum, n, maxflips);
+    let Stats { summary, histogram, worst } = fannkuch(n, simd);
+    println!("{}\nPfannkuchen({}) = {}", summary.checksum, n, summary.maxflips);
+    println!("\nflips\tpermutations");
+    for (flip_count, &permutations) in histogram.iter().enumerate() {
+        println!("{}\t{}", flip_count, permutations);
+    }
+    println!("\n{} permutations need {} flips:", worst.len(), summary.maxflips);
+    for permutation_index in worst {
+        let perm: Vec<_> = unrank(n, permutation_index).iter()
+            .map(|i| (i + 1).to_string())
+            .collect();
+        println!("{}", perm.join(" "));
+    }
+}
+
+/// Differential test of the SIMD flips against `flips` for n <= 10.
+#[cfg(target_arch = "x86_64")]
+fn check_simd() {
+    if !is_x86_feature_detected!("ssse3") {
+        println!("SSSE3 not supported, nothing to check");
+        return;
+    }
+    for n in 1..=10 {
+        if let Err(e) = check::<simd::Simd>(n) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    println!("SIMD flips match for n <= 10");
+}
+#[cfg(not(target_arch = "x86_64"))]
+fn check_simd() {
+    println!("SIMD flips are only implemented on x86_64, nothing to check");
+}
+
+/// Differential test of `Wide`, used for n > 16, against `flips` for n <= 10.
+fn check_wide() {
+    for n in 1..=10 {
+        if let Err(e) = check::<Wide>(n) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    println!("wide flips match for n <= 10");
+}
+
+/// Check `unrank`, `rank` and `permutations` against each other, and against
+/// all the permutations in lexicographic order, for n <= 8. Also check `rank`
+/// against a naive ranking for a few permutations up to n = 10.
+fn check_rank() {
+    fn fail(msg: String) -> ! {
+        eprintln!("{}", msg);
+        std::process::exit(1);
+    }
+    // Step through the permutations in the order of `permute`, rotating the
+    // prefixes one at a time with the counters of the original benchmark,
+    // until reaching `perm`.
+    fn naive_rank(perm: &[u8]) -> u64 {
+        let mut current: Vec<u8> = (0..perm.len() as u8).collect();
+        let mut count = vec![0; perm.len()];
+        let mut index = 0;
+        while current != perm {
+            let mut r = 1;
+            loop {
+                current[..=r].rotate_left(1);
+                count[r] += 1;
+                if count[r] <= r {
+                    break;
+                }
+                count[r] = 0;
+                r += 1;
+            }
+            index += 1;
+        }
+        index
+    }
+    let fixed: [&[u8]; 8] = [
+        &[0],
+        &[1, 0],
+        &[2, 0, 1],
+        &[0, 3, 1, 2],
+        &[3, 1, 4, 0, 2],
+        &[6, 2, 5, 0, 7, 1, 3, 4],
+        &[9, 8, 7, 6, 5, 4, 3, 2, 1, 0],
+        &[4, 0, 9, 2, 7, 5, 1, 8, 3, 6],
+    ];
+    for perm in &fixed {
+        if rank(perm) != naive_rank(perm) {
+            fail(format!("rank({:?}) = {} instead of {}", perm, rank(perm), naive_rank(perm)));
+        }
+    }
+    for n in 1..=8 {
+        let perm_max = factorials(n)[n];
+        let mut all = Vec::new();
+        for (index, perm) in permutations(n, 0..perm_max).enumerate() {
+            let index = index as u64;
+            if perm != unrank(n, index) {
+                fail(format!("n = {}: permutation {} is {:?}, unrank gives {:?}",
+                             n, index, perm, unrank(n, index)));
+            }
+            if rank(&perm) != index {
+                fail(format!("n = {}: rank({:?}) = {} instead of {}", n, perm, rank(&perm), index));
+            }
+            all.push(perm);
+        }
+        let middle: Vec<_> = permutations(n, perm_max / 3..perm_max / 2).collect();
+        if middle[..] != all[perm_max as usize / 3..perm_max as usize / 2] {
+            fail(format!("n = {}: permutations over a range differ", n));
+        }
+
+        // Naive lexicographic enumeration, by repeatedly finding the next one.
+        let mut lexicographic = Vec::new();
+        let mut perm: Vec<u8> = (0..n as u8).collect();
+        loop {
+            lexicographic.push(perm.clone());
+            let i = match (1..n).rev().find(|&i| perm[i - 1] < perm[i]) {
+                Some(i) => i,
+                None => break,
+            };
+            let j = (i..n).rev().find(|&j| perm[j] > perm[i - 1]).unwrap();
+            perm.swap(i - 1, j);
+            perm[i..].reverse();
+        }
+        all.sort();
+        if all != lexicographic {
+            fail(format!("n = {}: permutations are not all the permutations", n));
+        }
+    }
+    println!("rank, unrank and permutations match for n <= 8, and a naive rank for n <= 10");
 }
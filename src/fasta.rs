@@ -5,10 +5,17 @@
 // contributed by TeXitoi
 // multi-threaded version contributed by Alisdair Owens
 
+extern crate flate2;
+
+use flate2::{Compression, Crc};
+use flate2::write::{DeflateEncoder, GzEncoder};
 use std::cmp::min;
+use std::collections::BTreeMap;
+use std::fs;
 use std::io;
-use std::io::{Write, BufWriter, ErrorKind};
-use std::sync::{Mutex,Arc};
+use std::io::{Write, ErrorKind};
+use std::sync::{Condvar, Mutex, Arc};
+use std::sync::mpsc::{sync_channel, SyncSender};
 use std::thread;
 
 const LINE_LENGTH: usize = 60;
@@ -16,93 +23,330 @@ const IM: u32 = 139968;
 const LINES: usize = 1024;
 const BLKLEN: usize = LINE_LENGTH * LINES;
 
-struct MyStdOut {
-    thread_count: u16,
-    next_thread_num: u16,
-    stdout: io::Stdout,
+/// The sections written by default, in the format read by `parse_spec`.
+const DEFAULT_SPEC: &str = "\
+>ONE Homo sapiens alu
+repeat 2
+GGCCGGGCGCGGTGGCTCACGCCTGTAATCCCAGCACTTT
+GGGAGGCCGAGGCGGGCGGATCACCTGAGGTCAGGAGTTC
+GAGACCAGCCTGGCCAACATGGTGAAACCCCGTCTCTACT
+AAAAATACAAAAATTAGCCGGGCGTGGTGGCGCGCGCCTG
+TAATCCCAGCTACTCGGGAGGCTGAGGCAGGAGAATCGCT
+TGAACCCGGGAGGCGGAGGTTGCAGTGAGCCGAGATCGCG
+CCACTGCACTCCAGCCTGGGCGACAGAGCGAGACTCCGTCT
+CAAAAA
+
+>TWO IUB ambiguity codes
+random 3
+a 0.27
+c 0.12
+g 0.12
+t 0.27
+B 0.02
+D 0.02
+H 0.02
+K 0.02
+M 0.02
+N 0.02
+R 0.02
+S 0.02
+V 0.02
+W 0.02
+Y 0.02
+
+>THREE Homo sapiens frequency
+random 5
+a 0.3029549426680
+c 0.1979883004921
+g 0.1975473066391
+t 0.3015094502008
+";
+
+/// How the sequence of a section is generated.
+enum Kind {
+    /// Repeat this sequence.
+    Repeat(Vec<u8>),
+    /// Draw each letter at random with the given probability.
+    Random(Vec<(char, f32)>),
 }
 
-struct MyRandom {
-    last: u32,
-    count: usize,
-    thread_count: u16,
-    next_thread_num: u16,
+/// A section of the output: `header` followed by `n * multiplier` letters.
+struct Section {
+    header: String,
+    multiplier: usize,
+    kind: Kind,
 }
 
-impl MyRandom {
-    fn new(count: usize, thread_count: u16) -> MyRandom {
-        MyRandom {
-            last: 42,
-            count: count,
-            thread_count: thread_count,
-            next_thread_num: 0
+/// Parse a description of the sections to write. Each section is a header
+/// line (starting with `>`), then either `repeat <multiplier>` followed by the
+/// lines of the sequence to repeat, or `random <multiplier>` followed by lines
+/// `<letter> <probability>`. Blank lines and lines starting with `#` are
+/// ignored.
+fn parse_spec(spec: &str) -> io::Result<Vec<Section>> {
+    let invalid = |line: usize, msg: &str| io::Error::new(
+        ErrorKind::InvalidData, format!("line {}: {}", line + 1, msg));
+    let mut sections: Vec<Section> = Vec::new();
+    let mut lines = spec.lines().enumerate()
+        .map(|(i, l)| (i, l.trim()))
+        .filter(|&(_, l)| !l.is_empty() && !l.starts_with('#'))
+        .peekable();
+    while let Some((i, header)) = lines.next() {
+        if !header.starts_with('>') {
+            return Err(invalid(i, "expected a header starting with '>'"));
         }
+        let (i, kind) = lines.next().ok_or_else(|| invalid(i, "missing section kind"))?;
+        let mut words = kind.split_whitespace();
+        let (kind, multiplier) = match (words.next(), words.next().map(str::parse)) {
+            (Some(kind), Some(Ok(multiplier))) => (kind, multiplier),
+            _ => return Err(invalid(i, "expected 'repeat <multiplier>' or 'random <multiplier>'")),
+        };
+
+        let mut body = Vec::new();
+        while let Some(&(i, line)) = lines.peek() {
+            if line.starts_with('>') {
+                break;
+            }
+            body.push((i, line));
+            lines.next();
+        }
+        let kind = match kind {
+            "repeat" => {
+                let seq: Vec<u8> = body.iter().flat_map(|&(_, l)| l.bytes()).collect();
+                if seq.is_empty() {
+                    return Err(invalid(i, "empty sequence to repeat"));
+                }
+                Kind::Repeat(seq)
+            }
+            "random" => {
+                let mut probabilities = Vec::new();
+                for &(i, line) in &body {
+                    let mut words = line.split_whitespace();
+                    let letter = words.next().unwrap().chars().next().unwrap();
+                    match words.next().map(str::parse) {
+                        Some(Ok(p)) => probabilities.push((letter, p)),
+                        _ => return Err(invalid(i, "expected '<letter> <probability>'")),
+                    }
+                }
+                let total: f32 = probabilities.iter().map(|&(_, p)| p).sum();
+                if (total - 1.).abs() > 1e-3 {
+                    return Err(invalid(i, "probabilities don't add up to 1"));
+                }
+                Kind::Random(probabilities)
+            }
+            _ => return Err(invalid(i, "unknown section kind")),
+        };
+        sections.push(Section {
+            header: format!("{}\n", header),
+            multiplier: multiplier,
+            kind: kind,
+        });
     }
+    Ok(sections)
+}
 
-    fn normalize(p: f32) -> u32 {(p * IM as f32).floor() as u32}
+/// The source of the random numbers in `0..IM`.
+enum Generator {
+    /// The linear congruential generator required by the benchmark, with its
+    /// last value.
+    Lcg(u32),
+    /// xoshiro256** (http://prng.di.unimi.it/), for better quality data.
+    Xoshiro([u64; 4]),
+}
 
-    fn reset(&mut self, count: usize) {
-        self.next_thread_num = 0;
-        self.count = count;
+impl Generator {
+    /// The benchmark generator, which the benchmark seeds with 42. The seed is
+    /// taken modulo `IM`.
+    fn lcg(seed: u64) -> Generator {
+        Generator::Lcg((seed % IM as u64) as u32)
     }
 
-    fn gen(&mut self, buf: &mut [u32], cur_thread: u16) -> Result<usize,()> {
-
-        if self.next_thread_num != cur_thread {
-            return Err(())
+    /// A xoshiro256** generator, with its state filled from `seed` by
+    /// splitmix64 as recommended by its authors.
+    fn xoshiro(mut seed: u64) -> Generator {
+        let mut state = [0; 4];
+        for s in &mut state {
+            seed = seed.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            *s = z ^ (z >> 31);
         }
+        Generator::Xoshiro(state)
+    }
 
-        self.next_thread_num+=1;
-        if self.next_thread_num == self.thread_count {
-            self.next_thread_num = 0;
+    fn next(&mut self) -> u32 {
+        let mut x = [0];
+        self.fill(&mut x);
+        x[0]
+    }
+
+    /// A number in `0..n`, from as many draws as needed to cover the range.
+    /// Each draw of the LCG determines the next, so past `IM` it only gives
+    /// `IM` of the numbers.
+    fn below(&mut self, n: usize) -> usize {
+        let (mut x, mut range) = (0u64, 1u64);
+        while range < n as u64 {
+            x = x * IM as u64 + self.next() as u64;
+            range *= IM as u64;
         }
+        (x % n as u64) as usize
+    }
 
-        let to_gen = min(buf.len(), self.count);
-        for i in 0..to_gen {
-            self.last = (self.last * 3877 + 29573) % IM;
-            buf[i] = self.last;
+    /// A number in `0..1`.
+    fn uniform(&mut self) -> f64 {
+        self.next() as f64 / IM as f64
+    }
+
+    fn fill(&mut self, buf: &mut [u32]) {
+        match *self {
+            Generator::Lcg(ref mut last) => for x in buf {
+                *last = (*last * 3877 + 29573) % IM;
+                *x = *last;
+            },
+            Generator::Xoshiro(ref mut s) => for x in buf {
+                let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+                let t = s[1] << 17;
+                s[2] ^= s[0];
+                s[3] ^= s[1];
+                s[1] ^= s[2];
+                s[0] ^= s[3];
+                s[2] ^= t;
+                s[3] = s[3].rotate_left(45);
+                // Scale the high 32 bits down to `0..IM`.
+                *x = ((result >> 32) * IM as u64 >> 32) as u32;
+            },
         }
-        self.count -= to_gen;
-        Ok(to_gen)
     }
 }
 
-impl MyStdOut {
-    fn new(thread_count: u16) -> MyStdOut {
-        MyStdOut {
-            thread_count: thread_count,
-            next_thread_num: 0,
-            stdout: io::stdout()
+/// How the output is encoded. Compressed output is made of independent gzip
+/// members, one per block, which can then be compressed in parallel.
+#[derive(Clone, Copy)]
+enum Format {
+    Plain,
+    Gzip,
+    /// The BGZF variant of gzip used by samtools: members are at most 64 KiB,
+    /// record their size, and the file ends with an empty member, which makes
+    /// it indexable.
+    Bgzf,
+}
+
+/// Maximum number of bytes in a BGZF member, before and after compression.
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+/// The empty member at the end of a BGZF file.
+const BGZF_EOF: [u8; 28] = [31, 139, 8, 4, 0, 0, 0, 0, 0, 255, 6, 0, b'B', b'C', 2, 0,
+                            27, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+impl Format {
+    fn encode(self, data: Vec<u8>) -> Vec<u8> {
+        match self {
+            Format::Plain => data,
+            Format::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Format::Bgzf => {
+                let mut res = Vec::new();
+                for data in data.chunks(BGZF_BLOCK_SIZE) {
+                    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                    encoder.write_all(data).unwrap();
+                    let compressed = encoder.finish().unwrap();
+                    let mut crc = Crc::new();
+                    crc.update(data);
+
+                    // Header with the BC extra field holding the member size - 1.
+                    let size = 18 + compressed.len() + 8;
+                    assert!(size <= 0x10000, "BGZF block too large");
+                    res.extend_from_slice(&[31, 139, 8, 4, 0, 0, 0, 0, 0, 255, 6, 0, b'B', b'C', 2, 0]);
+                    res.extend_from_slice(&(size as u16 - 1).to_le_bytes());
+                    res.extend_from_slice(&compressed);
+                    res.extend_from_slice(&crc.sum().to_le_bytes());
+                    res.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                }
+                res
+            }
         }
     }
-    fn write(&mut self, data: &[u8], cur_thread: u16) -> io::Result<()> {
-        if self.next_thread_num != cur_thread {
-            return Err(io::Error::new(ErrorKind::Other, ""));
+
+    /// What must be written at the end of the output.
+    fn trailer(self) -> &'static [u8] {
+        match self {
+            Format::Bgzf => &BGZF_EOF,
+            _ => &[],
         }
+    }
+}
 
-        self.next_thread_num+=1;
-        if self.next_thread_num == self.thread_count {
-            self.next_thread_num = 0;
+/// The random numbers for the current section, drawn in blocks which are
+/// numbered so that they can be written in order.
+struct MyRandom {
+    generator: Generator,
+    count: usize,
+    next_block: u64,
+}
+
+impl MyRandom {
+    fn new(generator: Generator) -> MyRandom {
+        MyRandom {
+            generator: generator,
+            count: 0,
+            next_block: 0,
         }
+    }
 
-        self.stdout.write_all(data)
+    fn normalize(p: f32) -> u32 {(p * IM as f32).floor() as u32}
+
+    fn reset(&mut self, count: usize) {
+        self.next_block = 0;
+        self.count = count;
+    }
+
+    /// Fill `buf` with the next block, returning its number and length (0 at
+    /// the end of the section).
+    fn gen(&mut self, buf: &mut [u32]) -> (u64, usize) {
+        let to_gen = min(buf.len(), self.count);
+        self.generator.fill(&mut buf[..to_gen]);
+        self.count -= to_gen;
+        self.next_block += 1;
+        (self.next_block - 1, to_gen)
     }
 }
 
 fn make_random(data: &[(char, f32)]) -> Vec<(u32, u8)> {
     let mut acc = 0.;
-    data.iter()
+    let mut res: Vec<_> = data.iter()
         .map(|&(ch, p)| {
             acc += p;
             (MyRandom::normalize(acc), ch as u8)
         })
-        .collect()
+        .collect();
+    // The probabilities may not sum to exactly 1: make sure every random
+    // number picks a letter.
+    if let Some(last) = res.last_mut() {
+        last.0 = IM;
+    }
+    res
 }
 
-fn make_fasta2<I: Iterator<Item=u8>>(header: &str, mut it: I, mut n: usize)
+/// The letter drawn by the random number `rn`, given the cumulative
+/// probabilities from `make_random`.
+#[inline]
+fn pick(data: &[(u32, u8)], rn: u32) -> u8 {
+    for j in data {
+        if j.0 >= rn {
+            return j.1;
+        }
+    }
+    unreachable!("the last bound of make_random is IM")
+}
+
+fn make_fasta2<I: Iterator<Item=u8>>(format: Format, header: &str, mut it: I, mut n: usize)
     -> io::Result<()> {
-    let mut sysout = BufWriter::new(io::stdout());
-    try!(sysout.write_all(header.as_bytes()));
+    let stdout = io::stdout();
+    let mut sysout = stdout.lock();
+    let mut buf = Vec::with_capacity(BLKLEN + LINES);
+    buf.extend_from_slice(header.as_bytes());
     let mut line = [0u8; LINE_LENGTH + 1];
     while n > 0 {
         let nb = min(LINE_LENGTH, n);
@@ -111,63 +355,105 @@ fn make_fasta2<I: Iterator<Item=u8>>(header: &str, mut it: I, mut n: usize)
         }
         n -= nb;
         line[nb] = '\n' as u8;
-        try!(sysout.write_all(&line[..(nb+1)]));
+        if buf.len() + nb + 1 > BLKLEN + LINES {
+            try!(sysout.write_all(&format.encode(buf)));
+            buf = Vec::with_capacity(BLKLEN + LINES);
+        }
+        buf.extend_from_slice(&line[..(nb+1)]);
     }
-    Ok(())
+    sysout.write_all(&format.encode(buf))
+}
+
+/// A counting semaphore, bounding the number of blocks in flight in
+/// `make_fasta`.
+struct Permits {
+    count: Mutex<usize>,
+    released: Condvar,
 }
 
-fn do_fasta(thread_num: u16, rng: Arc<Mutex<MyRandom>>,
-            wr: Arc<Mutex<MyStdOut>>, data: Vec<(u32, u8)>) {
+impl Permits {
+    fn new(count: usize) -> Permits {
+        Permits { count: Mutex::new(count), released: Condvar::new() }
+    }
+
+    fn acquire(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count == 0 {
+            count = self.released.wait(count).unwrap();
+        }
+        *count -= 1;
+    }
+
+    fn release(&self) {
+        *self.count.lock().unwrap() += 1;
+        self.released.notify_one();
+    }
+}
+
+/// Turn blocks of random numbers into lines of letters, and send them to be
+/// written along with their block number. A block is only drawn with a
+/// permit, which the writer releases once the block is written.
+fn do_fasta(format: Format, rng: Arc<Mutex<MyRandom>>, data: Arc<Vec<(u32, u8)>>,
+            permits: Arc<Permits>, blocks: SyncSender<(u64, Vec<u8>)>) {
     let mut rng_buf = [0u32; BLKLEN];
-    let mut out_buf = [0u8; BLKLEN + LINES];
-    let mut count;
     loop {
-        loop {
-            if let Ok(x) = rng.lock().unwrap().gen(&mut rng_buf, thread_num) {
-                count = x;
-                break;
-            }
-        };
-
+        // Only the generator is shared: drawing the numbers is cheap, while
+        // the rest of the work is done in parallel.
+        permits.acquire();
+        let (block, count) = rng.lock().unwrap().gen(&mut rng_buf);
         if count == 0 {
+            // Let the other threads see the end too.
+            permits.release();
             break;
         }
+
+        let mut out_buf = vec![0u8; BLKLEN + LINES];
         let mut line_count = 0;
         for i in 0..count {
             if i % LINE_LENGTH == 0 && i > 0 {
                 out_buf[i+line_count] = b'\n';
                 line_count += 1;
             }
-            let rn = rng_buf[i];
-            for j in &data {
-                if j.0 >= rn {
-                    out_buf[i+line_count] = j.1;
-                    break;
-                }
-            }
+            out_buf[i+line_count] = pick(&data, rng_buf[i]);
         }
         out_buf[count+line_count] = b'\n';
+        out_buf.truncate(count + line_count + 1);
 
-        while let Err(_) = wr.lock()
-                .unwrap()
-                .write(&out_buf[..(count+line_count+1)], thread_num) {};
+        if blocks.send((block, format.encode(out_buf))).is_err() {
+            break;
+        }
     }
 }
 
-fn make_fasta(header: &str, rng: Arc<Mutex<MyRandom>>,
-                 data: Vec<(u32, u8)>, num_threads: u16
+fn make_fasta(format: Format, header: &str, rng: Arc<Mutex<MyRandom>>,
+                 data: Vec<(u32, u8)>, num_threads: usize
              ) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    try!(stdout.write_all(&format.encode(header.as_bytes().to_vec())));
 
-    let stdout = Arc::new(Mutex::new(MyStdOut::new(num_threads)));
-    try!(io::stdout().write_all(header.as_bytes()));
+    let data = Arc::new(data);
+    let (sender, blocks) = sync_channel(num_threads);
+    // Blocks may arrive out of order, and are kept until it's their turn:
+    // without a limit, a descheduled thread would let the others fill memory.
+    let permits = Arc::new(Permits::new(2 * num_threads));
     let mut threads = Vec::new();
-    for thread in 0..num_threads {
-        let d = data.clone();
-        let rng_clone = rng.clone();
-        let stdout_clone = stdout.clone();
-        threads.push(thread::spawn(move || {
-            do_fasta(thread, rng_clone, stdout_clone, d);
-        }));
+    for _ in 0..num_threads {
+        let (rng, data, sender) = (rng.clone(), data.clone(), sender.clone());
+        let permits = permits.clone();
+        threads.push(thread::spawn(move || do_fasta(format, rng, data, permits, sender)));
+    }
+    drop(sender);
+
+    let mut pending = BTreeMap::new();
+    let mut next_block = 0;
+    for (block, buf) in blocks {
+        pending.insert(block, buf);
+        while let Some(buf) = pending.remove(&next_block) {
+            try!(stdout.write_all(&buf));
+            permits.release();
+            next_block += 1;
+        }
     }
     for thread_guard in threads {
         thread_guard.join().unwrap();
@@ -175,44 +461,213 @@ fn make_fasta(header: &str, rng: Arc<Mutex<MyRandom>>,
     Ok(())
 }
 
+/// Simulated sequencing of the generated sequences.
+struct Reads {
+    count: usize,
+    length: usize,
+    /// Probability that a base is replaced by another.
+    substitution: f64,
+    /// Probability of an insertion or a deletion at each base, half each.
+    indel: f64,
+}
+
+/// The bases of a section, as `make_fasta` or `make_fasta2` would write them.
+fn generate(section: &Section, rng: &mut MyRandom, count: usize) -> Vec<u8> {
+    match section.kind {
+        Kind::Repeat(ref seq) => seq.iter().cycle().take(count).map(|c| *c).collect(),
+        Kind::Random(ref probabilities) => {
+            let data = make_random(probabilities);
+            let mut seq = Vec::with_capacity(count);
+            let mut rng_buf = [0u32; BLKLEN];
+            rng.reset(count);
+            loop {
+                let (_, len) = rng.gen(&mut rng_buf);
+                if len == 0 {
+                    break;
+                }
+                seq.extend(rng_buf[..len].iter().map(|&rn| pick(&data, rn)));
+            }
+            seq
+        }
+    }
+}
+
+/// Write `reads.count` reads sampled uniformly from the sections, with the
+/// errors of `reads` applied, as FASTQ. All bases get the Phred quality
+/// matching the error rate.
+fn make_fastq(format: Format, sections: &[Section], n: usize, rng: &mut MyRandom,
+              reads: &Reads) -> io::Result<()> {
+    const BASES: &[u8] = b"ACGT";
+    let seqs: Vec<_> = sections.iter()
+        .map(|section| {
+            let name = section.header[1..].split_whitespace().next().unwrap_or("");
+            (name, generate(section, rng, n * section.multiplier))
+        })
+        .collect();
+
+    // Reads start anywhere they fit, so sections are picked by their number
+    // of starting positions.
+    let starts: Vec<usize> = seqs.iter()
+        .map(|&(_, ref seq)| (seq.len() + 1).saturating_sub(reads.length))
+        .collect();
+    let total: usize = starts.iter().sum();
+    if total == 0 && reads.count > 0 {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "reads longer than every section"));
+    }
+
+    // The LCG can't pick among more than `IM` starts, so they are then drawn
+    // from a xoshiro256** generator seeded by it.
+    let mut positions = match rng.generator {
+        Generator::Lcg(_) if total > IM as usize => {
+            Some(Generator::xoshiro(rng.generator.next() as u64))
+        }
+        _ => None,
+    };
+
+    let error = reads.substitution + reads.indel;
+    let phred = if error > 0. { (-10. * error.log10()).round().max(0.).min(41.) } else { 41. };
+    let quality = vec![b'!' + phred as u8; reads.length];
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut buf = Vec::with_capacity(BLKLEN + LINES);
+    let mut read = Vec::with_capacity(reads.length);
+    for i in 0..reads.count {
+        let mut start = positions.as_mut().unwrap_or(&mut rng.generator).below(total);
+        let mut s = 0;
+        while start >= starts[s] {
+            start -= starts[s];
+            s += 1;
+        }
+        let (name, ref seq) = seqs[s];
+
+        read.clear();
+        let mut pos = start;
+        while read.len() < reads.length && pos < seq.len() {
+            let r = rng.generator.uniform();
+            if r < reads.substitution {
+                // One of the three other bases, in the same case.
+                let base = BASES[rng.generator.below(3)];
+                let base = if base == seq[pos].to_ascii_uppercase() { b'T' } else { base };
+                read.push(if seq[pos].is_ascii_lowercase() { base.to_ascii_lowercase() } else { base });
+                pos += 1;
+            } else if r < reads.substitution + reads.indel / 2. {
+                // A random base, in the case of the next one.
+                let base = BASES[rng.generator.below(4)];
+                read.push(if seq[pos].is_ascii_lowercase() { base.to_ascii_lowercase() } else { base });
+            } else if r < error {
+                pos += 1;
+            } else {
+                read.push(seq[pos]);
+                pos += 1;
+            }
+        }
+
+        if buf.len() + 2 * read.len() + 64 > BLKLEN + LINES {
+            stdout.write_all(&format.encode(buf))?;
+            buf = Vec::with_capacity(BLKLEN + LINES);
+        }
+        write!(buf, "@{}.{} {}:{}\n", name, i + 1, name, start + 1)?;
+        buf.extend_from_slice(&read);
+        buf.extend_from_slice(b"\n+\n");
+        buf.extend_from_slice(&quality[..read.len()]);
+        buf.push(b'\n');
+    }
+    stdout.write_all(&format.encode(buf))
+}
+
 fn main() {
-    let n = std::env::args_os().nth(1)
-        .and_then(|s| s.into_string().ok())
-        .and_then(|n| n.parse().ok())
-        .unwrap_or(1000);
-
-    let num_threads: u16 = 4;
-
-    let rng = Arc::new(Mutex::new(MyRandom::new(n*3, num_threads)));
-    let alu: &[u8] = b"GGCCGGGCGCGGTGGCTCACGCCTGTAATCCCAGCACTTT\
-                       GGGAGGCCGAGGCGGGCGGATCACCTGAGGTCAGGAGTTC\
-                       GAGACCAGCCTGGCCAACATGGTGAAACCCCGTCTCTACT\
-                       AAAAATACAAAAATTAGCCGGGCGTGGTGGCGCGCGCCTG\
-                       TAATCCCAGCTACTCGGGAGGCTGAGGCAGGAGAATCGCT\
-                       TGAACCCGGGAGGCGGAGGTTGCAGTGAGCCGAGATCGCG\
-                       CCACTGCACTCCAGCCTGGGCGACAGAGCGAGACTCCGTCT\
-                       CAAAAA";
-
-    let iub = &[('a', 0.27), ('c', 0.12), ('g', 0.12),
-                ('t', 0.27), ('B', 0.02), ('D', 0.02),
-                ('H', 0.02), ('K', 0.02), ('M', 0.02),
-                ('N', 0.02), ('R', 0.02), ('S', 0.02),
-                ('V', 0.02), ('W', 0.02), ('Y', 0.02)];
-
-    let homosapiens = &[('a', 0.3029549426680),
-                        ('c', 0.1979883004921),
-                        ('g', 0.1975473066391),
-                        ('t', 0.3015094502008)];
-
-    make_fasta2(">ONE Homo sapiens alu\n",
-                    alu.iter().cycle().map(|c| *c), n * 2).unwrap();
-    make_fasta(">TWO IUB ambiguity codes\n",
-                    rng.clone(), make_random(iub), num_threads).unwrap();
-
-    rng.lock().unwrap().reset(n*5);
-
-    make_fasta(">THREE Homo sapiens frequency\n",
-                    rng, make_random(homosapiens), num_threads).unwrap();
-
-    io::stdout().flush().unwrap();
+    let mut n = 1000;
+    let mut spec = DEFAULT_SPEC.to_string();
+    let (mut seed, mut make_generator): (u64, fn(u64) -> Generator) = (42, Generator::lcg);
+    let mut num_threads = thread::available_parallelism().map_or(4, |n| n.get());
+    let mut format = Format::Plain;
+    let mut fai = None;
+    let mut fastq = false;
+    let mut reads = Reads { count: 0, length: 100, substitution: 0., indel: 0. };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--spec" => {
+                let path = args.next().expect("--spec expects a file");
+                spec = fs::read_to_string(path).unwrap();
+            }
+            "--gzip" => format = Format::Gzip,
+            "--bgzip" => format = Format::Bgzf,
+            "--fai" => fai = Some(args.next().expect("--fai expects a file")),
+            "--fastq" => {
+                reads.count = args.next().and_then(|s| s.parse().ok())
+                    .expect("--fastq expects a number of reads");
+                fastq = true;
+            }
+            "--read-length" => {
+                reads.length = args.next().and_then(|s| s.parse().ok())
+                    .expect("--read-length expects a number");
+            }
+            "--substitution" => {
+                reads.substitution = args.next().and_then(|s| s.parse().ok())
+                    .expect("--substitution expects a probability");
+            }
+            "--indel" => {
+                reads.indel = args.next().and_then(|s| s.parse().ok())
+                    .expect("--indel expects a probability");
+            }
+            "--threads" => {
+                num_threads = args.next().and_then(|s| s.parse().ok()).filter(|&n| n > 0)
+                    .expect("--threads expects a positive number");
+            }
+            "--seed" => {
+                seed = args.next().and_then(|s| s.parse().ok()).expect("--seed expects a number");
+            }
+            "--rng" => make_generator = match args.next().as_ref().map(|s| &**s) {
+                Some("lcg") => Generator::lcg,
+                Some("xoshiro") => Generator::xoshiro,
+                _ => panic!("--rng expects lcg or xoshiro"),
+            },
+            _ => n = arg.parse().unwrap_or_else(|_| panic!("unknown argument {}", arg)),
+        }
+    }
+    let sections = parse_spec(&spec).unwrap();
+
+    // The random sections all draw from the same generator.
+    let rng = Arc::new(Mutex::new(MyRandom::new(make_generator(seed))));
+    if fastq {
+        assert!(fai.is_none(), "--fai only applies to FASTA output");
+        make_fastq(format, &sections, n, &mut rng.lock().unwrap(), &reads).unwrap();
+        io::stdout().write_all(format.trailer()).unwrap();
+        return;
+    }
+    let mut index = String::new();
+    let mut offset = 0;
+    for section in &sections {
+        let count = n * section.multiplier;
+
+        // samtools faidx entry: name, length, offset of the first base (in
+        // the uncompressed output), bases per line and bytes per line.
+        let name = section.header[1..].split_whitespace().next().unwrap_or("");
+        offset += section.header.len();
+        index.push_str(&format!("{}\t{}\t{}\t{}\t{}\n",
+                                name, count, offset, LINE_LENGTH, LINE_LENGTH + 1));
+        offset += count + (count + LINE_LENGTH - 1) / LINE_LENGTH;
+
+        match section.kind {
+            Kind::Repeat(ref seq) => {
+                make_fasta2(format, &section.header, seq.iter().cycle().map(|c| *c), count)
+                    .unwrap();
+            }
+            Kind::Random(ref probabilities) => {
+                rng.lock().unwrap().reset(count);
+                make_fasta(format, &section.header, rng.clone(),
+                           make_random(probabilities), num_threads).unwrap();
+            }
+        }
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(format.trailer()).unwrap();
+    stdout.flush().unwrap();
+    if let Some(path) = fai {
+        fs::write(path, index).unwrap();
+    }
 }
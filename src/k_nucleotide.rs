@@ -5,16 +5,26 @@
 // contributed by Cristi Cobzarenco (@cristicbz)
 // contributed by TeXitoi
 
-extern crate futures;
-extern crate futures_cpupool;
 extern crate indexmap;
+extern crate rayon;
 
-use std::sync::Arc;
-use std::hash::{Hasher, BuildHasherDefault};
-use futures::Future;
-use futures_cpupool::CpuPool;
+use std::cmp::{max, min};
+use std::collections::BinaryHeap;
+use std::convert::TryInto;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::hash::{Hash, Hasher, BuildHasherDefault};
 use Item::*;
 use indexmap::IndexMap;
+use rayon::prelude::*;
+
+#[path = "common/fasta_reader.rs"]
+mod fasta_reader;
 
 struct NaiveHasher(u64);
 impl Default for NaiveHasher {
@@ -35,162 +45,1165 @@ impl Hasher for NaiveHasher {
 }
 type NaiveBuildHasher = BuildHasherDefault<NaiveHasher>;
 type NaiveHashMap<K, V> = IndexMap<K, V, NaiveBuildHasher>;
-type Map = NaiveHashMap<Code, u32>;
-
-#[derive(Hash, PartialEq, PartialOrd, Ord, Eq, Clone, Copy)]
-struct Code(u64);
-impl Code {
-    fn push(&mut self, c: u8, mask: u64) {
-        self.0 <<= 2;
-        self.0 |= c as u64;
-        self.0 &= mask;
-    }
-    fn from_str(s: &str) -> Code {
-        let mask = Code::make_mask(s.len());
-        let mut res = Code(0);
+type Map<W> = NaiveHashMap<Code<W>, u32>;
+/// Counts which may exceed `u32`, for sequences spilled to disk.
+type BigMap<W> = NaiveHashMap<Code<W>, u64>;
+
+/// Storage for a k-mer code, 2 bits per base with the last base in the low
+/// bits.
+trait Word: Copy + Ord + Send + Sync + 'static {
+    fn zero() -> Self;
+    /// The bits used by a k-mer of length `frame`.
+    fn mask(frame: usize) -> Self;
+    /// Shift in the base `c`, keeping the bits in `mask`.
+    fn push(self, c: u8, mask: Self) -> Self;
+    /// The last base, and the code without it.
+    fn pop(self) -> (u8, Self);
+    /// Drop the last base and put `c` first, in a k-mer of length `frame`.
+    fn push_front(self, c: u8, frame: usize) -> Self;
+    /// The bits folded to 64 for hashing.
+    fn fold(self) -> u64;
+    /// The highest 64 bits of a k-mer of length `frame`, left aligned, which
+    /// order k-mers like their codes.
+    fn high(self, frame: usize) -> u64;
+    /// Size of the word when written to disk.
+    const BYTES: usize;
+    fn write_to(self, out: &mut [u8]);
+    fn read_from(buf: &[u8]) -> Self;
+}
+impl Word for u64 {
+    fn zero() -> u64 {
+        0
+    }
+    fn mask(frame: usize) -> u64 {
+        !0 >> (64 - 2 * frame)
+    }
+    fn push(self, c: u8, mask: u64) -> u64 {
+        (self << 2 | c as u64) & mask
+    }
+    fn pop(self) -> (u8, u64) {
+        (self as u8 & 0b11, self >> 2)
+    }
+    fn push_front(self, c: u8, frame: usize) -> u64 {
+        self >> 2 | (c as u64) << (2 * (frame - 1))
+    }
+    fn fold(self) -> u64 {
+        self
+    }
+    fn high(self, frame: usize) -> u64 {
+        self << (64 - 2 * frame)
+    }
+    const BYTES: usize = 8;
+    fn write_to(self, out: &mut [u8]) {
+        out.copy_from_slice(&self.to_le_bytes());
+    }
+    fn read_from(buf: &[u8]) -> u64 {
+        u64::from_le_bytes(buf.try_into().unwrap())
+    }
+}
+impl Word for u128 {
+    fn zero() -> u128 {
+        0
+    }
+    fn mask(frame: usize) -> u128 {
+        !0 >> (128 - 2 * frame)
+    }
+    fn push(self, c: u8, mask: u128) -> u128 {
+        (self << 2 | c as u128) & mask
+    }
+    fn pop(self) -> (u8, u128) {
+        (self as u8 & 0b11, self >> 2)
+    }
+    fn push_front(self, c: u8, frame: usize) -> u128 {
+        self >> 2 | (c as u128) << (2 * (frame - 1))
+    }
+    fn fold(self) -> u64 {
+        (self ^ self >> 64) as u64
+    }
+    fn high(self, frame: usize) -> u64 {
+        (self << (128 - 2 * frame) >> 64) as u64
+    }
+    const BYTES: usize = 16;
+    fn write_to(self, out: &mut [u8]) {
+        out.copy_from_slice(&self.to_le_bytes());
+    }
+    fn read_from(buf: &[u8]) -> u128 {
+        u128::from_le_bytes(buf.try_into().unwrap())
+    }
+}
+/// Several words, most significant first so that they compare like numbers.
+impl<const N: usize> Word for [u64; N] {
+    fn zero() -> [u64; N] {
+        [0; N]
+    }
+    fn mask(frame: usize) -> [u64; N] {
+        let mut res = [0; N];
+        for (i, w) in res.iter_mut().rev().enumerate() {
+            let bits = (2 * frame).saturating_sub(64 * i);
+            *w = if bits >= 64 { !0 } else if bits == 0 { 0 } else { !0 >> (64 - bits) };
+        }
+        res
+    }
+    fn push(mut self, c: u8, mask: [u64; N]) -> [u64; N] {
+        for i in 0..N - 1 {
+            self[i] = (self[i] << 2 | self[i + 1] >> 62) & mask[i];
+        }
+        self[N - 1] = (self[N - 1] << 2 | c as u64) & mask[N - 1];
+        self
+    }
+    fn pop(mut self) -> (u8, [u64; N]) {
+        let c = self[N - 1] as u8 & 0b11;
+        for i in (1..N).rev() {
+            self[i] = self[i] >> 2 | self[i - 1] << 62;
+        }
+        self[0] >>= 2;
+        (c, self)
+    }
+    fn push_front(self, c: u8, frame: usize) -> [u64; N] {
+        let (_, mut res) = self.pop();
+        let bit = 2 * (frame - 1);
+        res[N - 1 - bit / 64] |= (c as u64) << (bit % 64);
+        res
+    }
+    fn fold(self) -> u64 {
+        self.iter().fold(0, |h, &w| (h.rotate_left(5) ^ w).wrapping_mul(0x517cc1b727220a95))
+    }
+    fn high(self, frame: usize) -> u64 {
+        let shift = 64 * N - 2 * frame;
+        let (i, bits) = (shift / 64, shift % 64);
+        match self.get(i + 1) {
+            Some(&next) if bits > 0 => self[i] << bits | next >> (64 - bits),
+            _ => self[i] << bits,
+        }
+    }
+    const BYTES: usize = 8 * N;
+    fn write_to(self, out: &mut [u8]) {
+        for (w, out) in self.iter().zip(out.chunks_mut(8)) {
+            w.write_to(out);
+        }
+    }
+    fn read_from(buf: &[u8]) -> [u64; N] {
+        let mut res = [0; N];
+        for (w, buf) in res.iter_mut().zip(buf.chunks(8)) {
+            *w = u64::read_from(buf);
+        }
+        res
+    }
+}
+
+/// Longest k-mer that can be counted.
+const MAX_FRAME: usize = 1024;
+
+/// Evaluate `$e` with `$w` the narrowest `Word` that holds k-mers of length
+/// `$frame`.
+macro_rules! with_word {
+    ($frame:expr, $w:ident => $e:expr) => {
+        match $frame {
+            0..=32 => { type $w = u64; $e }
+            33..=64 => { type $w = u128; $e }
+            65..=128 => { type $w = [u64; 4]; $e }
+            129..=256 => { type $w = [u64; 8]; $e }
+            257..=512 => { type $w = [u64; 16]; $e }
+            _ => { type $w = [u64; MAX_FRAME / 32]; $e }
+        }
+    }
+}
+
+/// The 2-bit code of a base, or `AMBIGUOUS` for N, IUPAC codes or anything
+/// else that is not A, C, G or T in either case.
+fn encode(c: u8) -> u8 {
+    match c {
+        b'A' | b'C' | b'G' | b'T' | b'a' | b'c' | b'g' | b't' => (c & 0b110) >> 1,
+        _ => AMBIGUOUS,
+    }
+}
+const AMBIGUOUS: u8 = 4;
+/// Turns the code of a base into the code of its complement: A and T are 0
+/// and 2, C and G are 1 and 3.
+const COMPLEMENT: u8 = 0b10;
+
+#[derive(PartialEq, PartialOrd, Ord, Eq, Clone, Copy)]
+struct Code<W>(W);
+impl<W: Word> Hash for Code<W> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.0.fold());
+    }
+}
+impl<W: Word> Code<W> {
+    fn push(&mut self, c: u8, mask: W) {
+        self.0 = self.0.push(c, mask);
+    }
+    fn from_str(s: &str) -> Code<W> {
+        let mask = W::mask(s.len());
+        let mut res = Code(W::zero());
         for c in s.as_bytes() {
-            res.push(Code::encode(*c), mask);
+            res.push(encode(*c), mask);
+        }
+        res
+    }
+    /// Push the complement of `c` in front of the reverse complement of a
+    /// k-mer, to follow pushing `c` on the k-mer.
+    fn push_complement(&mut self, c: u8, frame: usize) {
+        self.0 = self.0.push_front(c ^ COMPLEMENT, frame);
+    }
+    fn reverse_complement(&self, frame: usize) -> Code<W> {
+        let (mut code, mut res) = (self.0, Code(W::zero()));
+        for _ in 0..frame {
+            let (c, rest) = code.pop();
+            res.push(c ^ COMPLEMENT, W::mask(frame));
+            code = rest;
         }
         res
     }
+    /// The smaller of the k-mer and its reverse complement, which is the
+    /// same for both strands.
+    fn canonical(&self, frame: usize) -> Code<W> {
+        min(*self, self.reverse_complement(frame))
+    }
     fn to_string(&self, frame: usize) -> String {
         let mut res = vec![];
         let mut code = self.0;
         for _ in 0..frame {
-            let c = match code as u8 & 0b11 {
-                c if c == Code::encode(b'A') => b'A',
-                c if c == Code::encode(b'T') => b'T',
-                c if c == Code::encode(b'G') => b'G',
-                c if c == Code::encode(b'C') => b'C',
+            let (c, rest) = code.pop();
+            let c = match c {
+                c if c == encode(b'A') => b'A',
+                c if c == encode(b'T') => b'T',
+                c if c == encode(b'G') => b'G',
+                c if c == encode(b'C') => b'C',
                 _ => unreachable!(),
             };
             res.push(c);
-            code >>= 2;
+            code = rest;
         }
         res.reverse();
         String::from_utf8(res).unwrap()
     }
-    fn make_mask(frame: usize) -> u64 {
-        (1u64 << (2 * frame)) - 1
-    }
-    fn encode(c: u8) -> u8 {
-        (c & 0b110) >> 1
-    }
 }
 
-struct Iter<'a> {
+struct Iter<'a, W> {
     iter: std::slice::Iter<'a, u8>,
-    code: Code,
-    mask: u64,
+    code: Code<W>,
+    mask: W,
+    /// The reverse complement of `code`, when counting canonical k-mers.
+    rc: Option<Code<W>>,
+    frame: usize,
 }
-impl<'a> Iter<'a> {
-    fn new(input: &[u8], frame: usize) -> Iter {
+impl<'a, W: Word> Iter<'a, W> {
+    fn new(input: &'a [u8], frame: usize, canonical: bool) -> Iter<'a, W> {
         let mut iter = input.iter();
-        let mut code = Code(0);
-        let mask = Code::make_mask(frame);
+        let mut code = Code(W::zero());
+        let mut rc = Code(W::zero());
+        let mask = W::mask(frame);
         for c in iter.by_ref().take(frame - 1) {
             code.push(*c, mask);
+            rc.push_complement(*c, frame);
         }
         Iter {
             iter: iter,
             code: code,
             mask: mask,
+            rc: if canonical { Some(rc) } else { None },
+            frame: frame,
         }
     }
 }
-impl<'a> Iterator for Iter<'a> {
-    type Item = Code;
+impl<'a, W: Word> Iterator for Iter<'a, W> {
+    type Item = Code<W>;
     fn next(&mut self) -> Option<Self::Item> {
         self.iter.next().map(|&c| {
             self.code.push(c, self.mask);
-            self.code
+            match self.rc {
+                Some(ref mut rc) => {
+                    rc.push_complement(c, self.frame);
+                    min(self.code, *rc)
+                }
+                None => self.code,
+            }
         })
     }
 }
 
-fn gen_freq(input: &[u8], frame: usize) -> Map {
-    let mut freq = Map::default();
-    for code in Iter::new(input, frame) {
-        *freq.entry(code).or_insert(0) += 1;
+/// Call `f` on the start and code of each k-mer of `input`, skipping those
+/// with ambiguous bases.
+fn for_each_kmer<W: Word, F: FnMut(usize, Code<W>)>(input: &[u8], frame: usize, canonical: bool,
+                                                    mut f: F) {
+    for part in input.split(|&c| c == AMBIGUOUS).filter(|part| part.len() >= frame) {
+        let start = part.as_ptr() as usize - input.as_ptr() as usize;
+        for (i, code) in Iter::new(part, frame, canonical).enumerate() {
+            f(start + i, code);
+        }
     }
+}
+
+fn gen_freq_seq<W: Word>(input: &[u8], frame: usize, canonical: bool) -> Map<W> {
+    let mut freq = Map::default();
+    for_each_kmer(input, frame, canonical, |_, code| *freq.entry(code).or_insert(0) += 1);
     freq
 }
 
-#[derive(Clone, Copy)]
+/// Inputs are not split in chunks smaller than this.
+const MIN_CHUNK_SIZE: usize = 1 << 20;
+
+/// Count the k-mers of `input` like `gen_freq_seq`, with the k-mers starting
+/// in each chunk of the input counted in parallel, then merged.
+fn gen_freq<W: Word>(input: &[u8], frame: usize, canonical: bool) -> Map<W> {
+    let threads = rayon::current_num_threads();
+    let chunk_size = max(MIN_CHUNK_SIZE, (input.len() + threads - 1) / threads);
+    let chunks = (input.len() + chunk_size - 1) / chunk_size;
+    (0..chunks).into_par_iter()
+        .map(|i| {
+            // The chunk overlaps the next one by k - 1 bases to get the
+            // k-mers starting at its end.
+            let start = i * chunk_size;
+            let end = min(start + chunk_size + frame - 1, input.len());
+            gen_freq_seq(&input[start..end], frame, canonical)
+        })
+        .reduce(Map::default, |a, b| {
+            let (mut a, b) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+            for (code, count) in b {
+                *a.entry(code).or_insert(0) += count;
+            }
+            a
+        })
+}
+
+#[derive(Clone)]
 enum Item {
     Freq(usize),
-    Occ(&'static str),
+    Occ(String),
+    /// Write the counts of the k-mers of a length to a database.
+    Export(usize, PathBuf),
 }
 impl Item {
-    fn print(&self, freq: &Map) {
+    /// The output for the counts of the item. Exports have none, their
+    /// counts are written by `export`.
+    fn format<W: Word>(&self, freq: &Map<W>, canonical: bool) -> String {
+        let mut res = String::new();
         match *self {
             Freq(frame) => {
                 let mut v: Vec<_> = freq.iter().map(|(&code, &count)| (count, code)).collect();
                 v.sort();
-                let total = v.iter().map(|&(count, _)| count).sum::<u32>() as f32;
+                let total = v.iter().map(|&(count, _)| count as u64).sum::<u64>() as f32;
                 for &(count, key) in v.iter().rev() {
-                    println!("{} {:.3}", key.to_string(frame), (count as f32 * 100.) / total);
+                    writeln!(res, "{} {:.3}", key.to_string(frame), (count as f32 * 100.) / total)
+                        .unwrap();
                 }
-                println!("");
+                res.push('\n');
+            }
+            Occ(ref occ) => {
+                let count = self.query(canonical).and_then(|code| freq.get(&code)).map_or(0, |&c| c);
+                writeln!(res, "{}\t{}", count, occ).unwrap();
             }
-            Occ(occ) => println!("{}\t{}", freq[&Code::from_str(occ)], occ),
+            Export(..) => (),
+        }
+        res
+    }
+    /// Write the counts of an `Export` item to its database.
+    fn export<W: Word>(&self, freq: &Map<W>, canonical: bool) -> io::Result<()> {
+        match *self {
+            Export(frame, ref path) => db::write(path, frame, canonical, freq)
+                .map_err(|e| db::write_error(path, e)),
+            Freq(_) | Occ(_) => Ok(()),
         }
     }
-    fn gen_freq(&self, input: &[u8]) -> Map {
+    /// The code of the k-mer to look up, if any.
+    fn query<W: Word>(&self, canonical: bool) -> Option<Code<W>> {
         match *self {
-            Freq(frame) => gen_freq(input, frame),
-            Occ(occ) => gen_freq(input, occ.len()),
+            Freq(_) | Export(..) => None,
+            Occ(ref occ) if canonical => Some(Code::from_str(occ).canonical(occ.len())),
+            Occ(ref occ) => Some(Code::from_str(occ)),
         }
     }
+    fn frame(&self) -> usize {
+        match *self {
+            Freq(frame) | Export(frame, _) => frame,
+            Occ(ref occ) => occ.len(),
+        }
+    }
+    fn count<W: Word>(&self, input: &[u8], canonical: bool) -> io::Result<(String, usize)> {
+        let freq = gen_freq::<W>(input, self.frame(), canonical);
+        let windows = input.len().saturating_sub(self.frame() - 1);
+        let skipped = windows - freq.values().map(|&count| count as usize).sum::<usize>();
+        self.export(&freq, canonical)?;
+        Ok((self.format(&freq, canonical), skipped))
+    }
+    /// Count the k-mers with the narrowest code that holds them, and format
+    /// the result or export it. Canonical k-mers are counted along with their
+    /// reverse complement. Also returns the number of k-mers skipped because
+    /// of ambiguous bases.
+    fn run(&self, input: &[u8], canonical: bool) -> io::Result<(String, usize)> {
+        with_word!(self.frame(), W => self.count::<W>(input, canonical))
+    }
+    /// Like `run`, counting the k-mers of a spilled sequence and writing the
+    /// result to `out` as it goes.
+    fn run_spilled<O: Write>(&self, spill: &Spill, canonical: bool, out: &mut O)
+                             -> io::Result<usize> {
+        with_word!(self.frame(), W => spill.count::<W, O>(self, canonical, out))
+    }
+}
+const FRAMES: [usize; 2] = [1, 2];
+const QUERIES: [&str; 5] = ["GGT", "GGTA", "GGTATT", "GGTATTTTAATT", "GGTATTTTAATTTATAGT"];
+
+/// Parse a comma separated list of frame lengths and ranges like `1-4`.
+fn parse_frames(s: &str) -> Vec<usize> {
+    let parse = |s: &str| match s.parse() {
+        Ok(frame) if frame >= 1 && frame <= MAX_FRAME => frame,
+        _ => panic!("invalid frame length {:?}, expected 1 to {}", s, MAX_FRAME),
+    };
+    let mut frames = vec![];
+    for part in s.split(',') {
+        match part.find('-') {
+            Some(i) => frames.extend(parse(&part[..i])..parse(&part[i + 1..]) + 1),
+            None => frames.push(parse(part)),
+        }
+    }
+    frames
+}
+
+fn parse_query(s: &str) -> String {
+    if s.is_empty() || s.len() > MAX_FRAME
+        || !s.bytes().all(|c| b"ACGTacgt".contains(&c)) {
+        panic!("invalid query {:?}, expected 1 to {} of ACGT", s, MAX_FRAME);
+    }
+    s.to_string()
+}
+
+
+/// Size of the pieces of sequence read at a time by `Spill`.
+const SPILL_CHUNK_SIZE: usize = 16 << 20;
+
+/// A sequence encoded to a file, for counting k-mers with bounded memory in
+/// the way of KMC or Jellyfish: the k-mers are written to bucket files, then
+/// each bucket is counted on its own, so that only a few buckets are in memory
+/// at a time. The buckets of a `Freq` item are sorted to files and merged, and
+/// those of an `Export` item hold ranges of codes, appended to the database in
+/// order. An `Occ` item needs no buckets.
+struct Spill {
+    dir: PathBuf,
+    buckets: usize,
+    len: usize,
 }
-static ITEMS: [Item; 7] = [
-    Freq(1),
-    Freq(2),
-    Occ("GGT"),
-    Occ("GGTA"),
-    Occ("GGTATT"),
-    Occ("GGTATTTTAATT"),
-    Occ("GGTATTTTAATTTATAGT"),
-];
+impl Spill {
+    /// Encode the sequence of the current record of `reader` to `dir`.
+    fn new<R: BufRead>(reader: &mut fasta_reader::Reader<R>, dir: PathBuf, buckets: usize)
+                       -> io::Result<Spill> {
+        let mut out = BufWriter::new(File::create(dir.join("sequence"))?);
+        let mut buf = Vec::with_capacity(SPILL_CHUNK_SIZE);
+        let mut len = 0;
+        loop {
+            buf.clear();
+            let more = reader.read_seq(&mut buf, SPILL_CHUNK_SIZE)?;
+            for c in &mut buf {
+                *c = encode(*c);
+            }
+            out.write_all(&buf)?;
+            len += buf.len();
+            if !more {
+                break;
+            }
+        }
+        out.flush()?;
+        Ok(Spill { dir: dir, buckets: buckets, len: len })
+    }
+
+    fn bucket_path(&self, bucket: usize) -> PathBuf {
+        self.dir.join(format!("bucket-{}", bucket))
+    }
 
+    fn run_path(&self, bucket: usize) -> PathBuf {
+        self.dir.join(format!("run-{}", bucket))
+    }
 
-fn get_seq<R: std::io::BufRead>(mut r: R, key: &[u8]) -> Vec<u8> {
-    let mut res = Vec::with_capacity(65536);
-    let mut line = Vec::with_capacity(64);
+    /// The bucket of a k-mer: by hash, or with `by_code` by the range of
+    /// codes it falls in. Ranges are of equal size, so canonical k-mers,
+    /// which are the smaller code of the two strands, leave the last buckets
+    /// emptier.
+    fn bucket<W: Word>(&self, code: Code<W>, frame: usize, by_code: bool) -> usize {
+        if by_code {
+            (code.0.high(frame) as u128 * self.buckets as u128 >> 64) as usize
+        } else {
+            (code.0.fold().wrapping_mul(0x9e3779b97f4a7c15) >> 32) as usize % self.buckets
+        }
+    }
 
-    loop {
-        match r.read_until(b'\n', &mut line) {
-            Ok(b) if b > 0 => if line.starts_with(key) { break },
-            _ => break,
+    /// Count the k-mers of a bucket.
+    fn count_bucket<W: Word>(&self, bucket: usize) -> io::Result<BigMap<W>> {
+        let mut freq = BigMap::default();
+        let mut input = BufReader::new(File::open(self.bucket_path(bucket))?);
+        let mut bytes = vec![0; W::BYTES];
+        loop {
+            match input.read_exact(&mut bytes) {
+                Ok(()) => *freq.entry(Code(W::read_from(&bytes))).or_insert(0) += 1,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(freq),
+                Err(e) => return Err(e),
+            }
         }
-        line.clear();
     }
 
-    loop {
-        line.clear();
-        match r.read_until(b'\n', &mut line) {
-            Ok(b) if b > 0 => res.extend(line[..line.len()-1].iter().cloned().map(Code::encode)),
-            _ => break,
+    /// Count the k-mers for `item`, and write the result like `Item::run`
+    /// to `out`. Returns the number of k-mers skipped.
+    fn count<W: Word, O: Write>(&self, item: &Item, canonical: bool, out: &mut O)
+                                -> io::Result<usize> {
+        let frame = item.frame();
+        let by_code = match *item {
+            Export(..) => true,
+            Freq(_) | Occ(_) => false,
+        };
+
+        // Write the k-mers to the buckets, reading the sequence in chunks
+        // which overlap by k - 1 bases. The occurrences of a query are
+        // counted on the way instead.
+        let query = item.query::<W>(canonical);
+        let mut buckets = Vec::with_capacity(self.buckets);
+        if query.is_none() {
+            for bucket in 0..self.buckets {
+                buckets.push(BufWriter::new(File::create(self.bucket_path(bucket))?));
+            }
+        }
+        let mut input = File::open(self.dir.join("sequence"))?;
+        let mut buf = Vec::with_capacity(SPILL_CHUNK_SIZE + frame);
+        let (mut counted, mut matches, mut bytes) = (0, 0u64, vec![0; W::BYTES]);
+        loop {
+            let done = buf.len().saturating_sub(frame - 1);
+            buf.drain(..done);
+            if (&mut input).take(SPILL_CHUNK_SIZE as u64).read_to_end(&mut buf)? == 0 {
+                break;
+            }
+            let mut res = Ok(());
+            for_each_kmer(&buf, frame, canonical, |_, code: Code<W>| {
+                counted += 1;
+                if let Some(query) = query {
+                    matches += (code == query) as u64;
+                    return;
+                }
+                code.0.write_to(&mut bytes);
+                if res.is_ok() {
+                    res = buckets[self.bucket(code, frame, by_code)].write_all(&bytes);
+                }
+            });
+            res?;
+        }
+        let written = !buckets.is_empty();
+        for mut bucket in buckets {
+            bucket.flush()?;
+        }
+
+        match *item {
+            Freq(_) => self.write_freq::<W, O>(frame, out)?,
+            Occ(ref occ) => writeln!(out, "{}\t{}", matches, occ)?,
+            Export(_, ref path) => {
+                self.export::<W>(path, frame, canonical).map_err(|e| db::write_error(path, e))?
+            }
+        }
+        if written {
+            for bucket in 0..self.buckets {
+                fs::remove_file(self.bucket_path(bucket))?;
+            }
         }
+
+        let windows = self.len.saturating_sub(frame - 1);
+        Ok(windows - counted)
     }
 
-    res
+    /// Write the frequencies of the k-mers in the buckets, like a `Freq` item:
+    /// each bucket is sorted by decreasing count to a file, then the files are
+    /// merged.
+    fn write_freq<W: Word, O: Write>(&self, frame: usize, out: &mut O) -> io::Result<()> {
+        let total = (0..self.buckets).into_par_iter()
+            .map(|bucket| -> io::Result<u64> {
+                let mut v: Vec<_> = self.count_bucket::<W>(bucket)?.into_iter()
+                    .map(|(code, count)| (count, code))
+                    .collect();
+                v.sort_by(|a, b| b.cmp(a));
+                let mut run = BufWriter::new(File::create(self.run_path(bucket))?);
+                let mut bytes = vec![0; W::BYTES];
+                for &(count, code) in &v {
+                    code.0.write_to(&mut bytes);
+                    run.write_all(&bytes)?;
+                    run.write_all(&count.to_le_bytes())?;
+                }
+                run.flush()?;
+                Ok(v.iter().map(|&(count, _)| count).sum())
+            })
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter().sum::<u64>() as f32;
+
+        let mut runs = Vec::with_capacity(self.buckets);
+        for bucket in 0..self.buckets {
+            runs.push(BufReader::new(File::open(self.run_path(bucket))?));
+        }
+        let mut buf = vec![0; W::BYTES + 8];
+        let mut next = |run: &mut BufReader<File>| -> io::Result<Option<(u64, Code<W>)>> {
+            match run.read_exact(&mut buf) {
+                Ok(()) => Ok(Some((u64::from_le_bytes(buf[W::BYTES..].try_into().unwrap()),
+                                   Code(W::read_from(&buf[..W::BYTES]))))),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+                Err(e) => Err(e),
+            }
+        };
+        let mut heap = BinaryHeap::with_capacity(self.buckets);
+        for (bucket, run) in runs.iter_mut().enumerate() {
+            if let Some((count, code)) = next(run)? {
+                heap.push((count, code, bucket));
+            }
+        }
+        while let Some((count, code, bucket)) = heap.pop() {
+            writeln!(out, "{} {:.3}", code.to_string(frame), (count as f32 * 100.) / total)?;
+            if let Some((count, code)) = next(&mut runs[bucket])? {
+                heap.push((count, code, bucket));
+            }
+        }
+        writeln!(out)?;
+        for bucket in 0..self.buckets {
+            fs::remove_file(self.run_path(bucket))?;
+        }
+        Ok(())
+    }
+
+    /// Write the k-mers in the buckets to a database, like an `Export` item.
+    /// The buckets hold ranges of codes, so they are sorted and appended in
+    /// order, a few at a time.
+    fn export<W: Word>(&self, path: &Path, frame: usize, canonical: bool) -> io::Result<()> {
+        let mut db = db::Writer::<W>::create(path, frame, canonical)?;
+        let buckets: Vec<_> = (0..self.buckets).collect();
+        for group in buckets.chunks(rayon::current_num_threads()) {
+            let entries = group.par_iter()
+                .map(|&bucket| -> io::Result<Vec<(Code<W>, u64)>> {
+                    let mut entries: Vec<_> = self.count_bucket::<W>(bucket)?.into_iter().collect();
+                    entries.sort();
+                    Ok(entries)
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            for (code, count) in entries.into_iter().flat_map(|entries| entries) {
+                db.push(code, count)?;
+            }
+        }
+        db.finish()
+    }
+}
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.dir.join("sequence"));
+    }
+}
+
+/// Databases of k-mer counts: a header, then the entries sorted by code, each
+/// the code in `Word::BYTES` bytes and the count in 4, in little endian.
+/// Counts saturate at `u32::MAX`.
+mod db {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::io::{Seek, SeekFrom};
+    use std::marker::PhantomData;
+    use std::path::Path;
+
+    const MAGIC: &[u8; 8] = b"KMERDB\0\x01";
+    const HEADER_SIZE: u64 = 24;
+
+    #[derive(Clone, Copy, PartialEq)]
+    struct Header {
+        frame: usize,
+        canonical: bool,
+        /// The number of entries.
+        len: u64,
+    }
+    impl Header {
+        fn write<O: Write>(&self, out: &mut O) -> io::Result<()> {
+            out.write_all(MAGIC)?;
+            out.write_all(&(self.frame as u32).to_le_bytes())?;
+            out.write_all(&(self.canonical as u32).to_le_bytes())?;
+            out.write_all(&self.len.to_le_bytes())
+        }
+        fn read<I: Read>(input: &mut I) -> io::Result<Header> {
+            let mut buf = [0; HEADER_SIZE as usize];
+            input.read_exact(&mut buf)?;
+            if &buf[..8] != MAGIC {
+                return Err(invalid_data("not a k-mer database"));
+            }
+            let frame = u32::from_le_bytes(buf[8..12].try_into().unwrap()) as usize;
+            if frame == 0 || frame > MAX_FRAME {
+                return Err(invalid_data("invalid k-mer length"));
+            }
+            Ok(Header {
+                frame: frame,
+                canonical: buf[12] != 0,
+                len: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+            })
+        }
+    }
+
+    fn invalid_data(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+
+    fn write_entry<W: Word, O: Write>(out: &mut O, buf: &mut [u8], code: Code<W>, count: u32)
+                                      -> io::Result<()> {
+        code.0.write_to(&mut buf[..W::BYTES]);
+        buf[W::BYTES..].copy_from_slice(&count.to_le_bytes());
+        out.write_all(buf)
+    }
+
+    fn read_entry<W: Word>(buf: &[u8]) -> (Code<W>, u32) {
+        (Code(W::read_from(&buf[..W::BYTES])), u32::from_le_bytes(buf[W::BYTES..].try_into().unwrap()))
+    }
+
+    /// An error writing the database `path`, saying which.
+    pub fn write_error(path: &Path, e: io::Error) -> io::Error {
+        io::Error::new(e.kind(), format!("cannot write {}: {}", path.display(), e))
+    }
+
+    /// Writes a new database, from entries in order.
+    pub struct Writer<W> {
+        out: BufWriter<File>,
+        header: Header,
+        buf: Vec<u8>,
+        word: PhantomData<W>,
+    }
+    impl<W: Word> Writer<W> {
+        pub fn create(path: &Path, frame: usize, canonical: bool) -> io::Result<Writer<W>> {
+            let mut out = BufWriter::new(File::create(path)?);
+            let header = Header { frame: frame, canonical: canonical, len: 0 };
+            header.write(&mut out)?;
+            Ok(Writer { out: out, header: header, buf: vec![0; W::BYTES + 4], word: PhantomData })
+        }
+        /// Append an entry, with a code greater than the last one. Counts
+        /// past `u32::MAX` are written as `u32::MAX`.
+        pub fn push(&mut self, code: Code<W>, count: u64) -> io::Result<()> {
+            let count = min(count, u32::max_value() as u64) as u32;
+            write_entry(&mut self.out, &mut self.buf, code, count)?;
+            self.header.len += 1;
+            Ok(())
+        }
+        /// Now that the number of entries is known, rewrite the header.
+        pub fn finish(self) -> io::Result<()> {
+            let mut out = self.out.into_inner().map_err(|e| e.into_error())?;
+            out.seek(SeekFrom::Start(0))?;
+            self.header.write(&mut out)
+        }
+    }
+
+    /// Write the entries of `freq` to a new database.
+    pub fn write<W: Word>(path: &Path, frame: usize, canonical: bool, freq: &Map<W>)
+                          -> io::Result<()> {
+        let mut entries: Vec<_> = freq.iter().map(|(&code, &count)| (code, count)).collect();
+        entries.sort();
+        let mut out = Writer::create(path, frame, canonical)?;
+        for (code, count) in entries {
+            out.push(code, count as u64)?;
+        }
+        out.finish()
+    }
+
+    /// Reads the entries of a database in order.
+    struct Entries<W> {
+        input: BufReader<File>,
+        left: u64,
+        buf: Vec<u8>,
+        word: PhantomData<W>,
+    }
+    impl<W: Word> Entries<W> {
+        fn new(input: BufReader<File>, header: &Header) -> Entries<W> {
+            Entries {
+                input: input,
+                left: header.len,
+                buf: vec![0; W::BYTES + 4],
+                word: PhantomData,
+            }
+        }
+    }
+    impl<W: Word> Iterator for Entries<W> {
+        type Item = io::Result<(Code<W>, u32)>;
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.left == 0 {
+                return None;
+            }
+            self.left -= 1;
+            Some(self.input.read_exact(&mut self.buf).map(|()| read_entry(&self.buf)))
+        }
+    }
+
+    fn open(path: &str) -> io::Result<(Header, BufReader<File>)> {
+        let mut input = BufReader::new(File::open(path)?);
+        let header = Header::read(&mut input)?;
+        Ok((header, input))
+    }
+
+    /// Print the counts of `queries`, found by binary search in the file.
+    fn lookup<W: Word>(path: &str, header: &Header, queries: &[String]) -> io::Result<()> {
+        let mut input = File::open(path)?;
+        let mut buf = vec![0; W::BYTES + 4];
+        for query in queries {
+            if query.len() != header.frame {
+                return Err(invalid_data("query of the wrong length for the database"));
+            }
+            let mut code = Code::<W>::from_str(query);
+            if header.canonical {
+                code = code.canonical(header.frame);
+            }
+            let (mut lo, mut hi, mut count) = (0, header.len, 0);
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                input.seek(SeekFrom::Start(HEADER_SIZE + mid * buf.len() as u64))?;
+                input.read_exact(&mut buf)?;
+                let (entry, entry_count) = read_entry::<W>(&buf);
+                if entry < code {
+                    lo = mid + 1;
+                } else if entry > code {
+                    hi = mid;
+                } else {
+                    count = entry_count;
+                    break;
+                }
+            }
+            println!("{}\t{}", count, query);
+        }
+        Ok(())
+    }
+
+    /// Print each k-mer with its count.
+    fn dump<W: Word>(input: BufReader<File>, header: &Header) -> io::Result<()> {
+        for entry in Entries::<W>::new(input, header) {
+            let (code, count) = entry?;
+            println!("{}\t{}", code.to_string(header.frame), count);
+        }
+        Ok(())
+    }
+
+    /// Print the k-mer spectrum: for each count, the number of k-mers with
+    /// that count.
+    fn histogram<W: Word>(input: BufReader<File>, header: &Header) -> io::Result<()> {
+        let mut histogram = BTreeMap::new();
+        for entry in Entries::<W>::new(input, header) {
+            *histogram.entry(entry?.1).or_insert(0u64) += 1;
+        }
+        for (count, kmers) in histogram {
+            println!("{}\t{}", count, kmers);
+        }
+        Ok(())
+    }
+
+    /// Write the k-mers of `a` also in `b`, with the smaller count, or with
+    /// `subtract` the k-mers of `a` not in `b`.
+    fn merge<W: Word>(a: (Header, BufReader<File>), b: (Header, BufReader<File>), out: &str,
+                      subtract: bool) -> io::Result<()> {
+        let (header, a) = a;
+        let mut out = Writer::create(Path::new(out), header.frame, header.canonical)?;
+        let mut b = Entries::<W>::new(b.1, &b.0).peekable();
+        for entry in Entries::<W>::new(a, &header) {
+            let (code, count) = entry?;
+            let mut in_b = None;
+            while let Some(&Ok((other, other_count))) = b.peek() {
+                if other > code {
+                    break;
+                }
+                if other == code {
+                    in_b = Some(other_count);
+                }
+                b.next();
+            }
+            if let Some(&Err(_)) = b.peek() {
+                return b.next().unwrap().map(|_| ());
+            }
+            let count = match (in_b, subtract) {
+                (Some(other_count), false) => min(count, other_count),
+                (None, true) => count,
+                _ => continue,
+            };
+            out.push(code, count as u64)?;
+        }
+        out.finish()
+    }
+
+    const USAGE: &str = "usage: k_nucleotide db lookup DB KMER[,KMER...]
+       k_nucleotide db dump DB
+       k_nucleotide db histogram DB
+       k_nucleotide db intersect|subtract DB DB OUT";
+
+    /// Run the `db` subcommand, with the arguments after it.
+    pub fn main<I: Iterator<Item = String>>(mut args: I) -> io::Result<()> {
+        let command = args.next().expect(USAGE);
+        let path = args.next().expect(USAGE);
+        let (header, input) = open(&path)?;
+        match &*command {
+            "lookup" => {
+                let queries: Vec<_> = args.next().expect(USAGE).split(',').map(parse_query).collect();
+                with_word!(header.frame, W => lookup::<W>(&path, &header, &queries))
+            }
+            "dump" => with_word!(header.frame, W => dump::<W>(input, &header)),
+            "histogram" => with_word!(header.frame, W => histogram::<W>(input, &header)),
+            "intersect" | "subtract" => {
+                let (other, out) = (args.next().expect(USAGE), args.next().expect(USAGE));
+                // `out` is truncated before the inputs are read.
+                let same = |a: &str, b: &str| match (fs::canonicalize(a), fs::canonicalize(b)) {
+                    (Ok(a), Ok(b)) => a == b,
+                    _ => false,
+                };
+                if same(&out, &path) || same(&out, &other) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "the output database is one of the inputs"));
+                }
+                let other = open(&other)?;
+                if (other.0.frame, other.0.canonical) != (header.frame, header.canonical) {
+                    return Err(invalid_data("the databases have different kinds of k-mers"));
+                }
+                let subtract = command == "subtract";
+                with_word!(header.frame, W => merge::<W>((header, input), other, &out, subtract))
+            }
+            _ => panic!("{}", USAGE),
+        }
+    }
+}
+
+/// Minimizers and MinHash sketches of records.
+mod sketch {
+    use super::*;
+    use std::collections::{BTreeSet, VecDeque};
+
+    /// A hash of a k-mer, to order k-mers pseudo-randomly: the finalizer of
+    /// MurmurHash3.
+    fn hash<W: Word>(code: Code<W>) -> u64 {
+        let mut h = code.0.fold();
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51afd7ed558ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+        h ^ h >> 33
+    }
+
+    /// Print the start and the k-mer of each minimizer of `seq`: the k-mer
+    /// with the smallest hash among `window` consecutive ones, leftmost on
+    /// ties. Windows do not span ambiguous bases.
+    fn minimizers<W: Word>(seq: &[u8], frame: usize, window: usize, canonical: bool) {
+        // The k-mers which may still be the minimizer of a window, by
+        // increasing start and hash.
+        let mut candidates: VecDeque<(usize, u64, Code<W>)> = VecDeque::new();
+        let (mut run_start, mut last, mut reported) = (0, None, None);
+        for_each_kmer(seq, frame, canonical, |start, code| {
+            if last.map_or(true, |last| last + 1 != start) {
+                candidates.clear();
+                run_start = start;
+            }
+            last = Some(start);
+            let h = hash(code);
+            while candidates.back().map_or(false, |&(_, other, _)| other > h) {
+                candidates.pop_back();
+            }
+            candidates.push_back((start, h, code));
+            while candidates[0].0 + window <= start {
+                candidates.pop_front();
+            }
+            let (min_start, _, min_code) = candidates[0];
+            if start + 1 >= run_start + window && reported != Some(min_start) {
+                println!("{}\t{}", min_start + 1, min_code.to_string(frame));
+                reported = Some(min_start);
+            }
+        });
+    }
+
+    /// The `size` smallest distinct hashes of the k-mers of `seq`, in order.
+    fn sketch<W: Word>(seq: &[u8], frame: usize, size: usize, canonical: bool) -> Vec<u64> {
+        let mut sketch = BTreeSet::new();
+        for_each_kmer(seq, frame, canonical, |_, code: Code<W>| {
+            let h = hash(code);
+            if sketch.len() < size || h < *sketch.iter().next_back().unwrap() {
+                sketch.insert(h);
+                if sketch.len() > size {
+                    let max = *sketch.iter().next_back().unwrap();
+                    sketch.remove(&max);
+                }
+            }
+        });
+        sketch.into_iter().collect()
+    }
+
+    /// The number of hashes in both sketches among the `size` smallest of
+    /// their union, and the number of hashes considered.
+    fn shared(a: &[u64], b: &[u64], size: usize) -> (usize, usize) {
+        let (mut i, mut j, mut shared, mut seen) = (0, 0, 0, 0);
+        while seen < size && i < a.len() && j < b.len() {
+            if a[i] == b[j] {
+                shared += 1;
+                i += 1;
+                j += 1;
+            } else if a[i] < b[j] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+            seen += 1;
+        }
+        (shared, min(size, seen + (a.len() - i) + (b.len() - j)))
+    }
+
+    const USAGE: &str = "usage: k_nucleotide minimizers [--canonical] K W RECORD
+       k_nucleotide sketch [--canonical] K SIZE RECORD RECORD";
+
+    fn parse(arg: Option<String>) -> usize {
+        arg.and_then(|s| s.parse().ok()).filter(|&n| n > 0).expect(USAGE)
+    }
+
+    /// Read the records named `ids` from stdin, encoded.
+    fn read_records(ids: &[String]) -> io::Result<Vec<Vec<u8>>> {
+        let mut res = vec![None; ids.len()];
+        let stdin = std::io::stdin();
+        for record in fasta_reader::Reader::new(stdin.lock()) {
+            let record = record?;
+            for (id, res) in ids.iter().zip(&mut res) {
+                if res.is_none() && record.id() == id.as_bytes() {
+                    *res = Some(record.seq.iter().map(|&c| encode(c)).collect());
+                }
+            }
+        }
+        ids.iter().zip(res)
+            .map(|(id, seq)| seq.ok_or_else(|| io::Error::new(
+                io::ErrorKind::NotFound, format!("no {} record in the input", id))))
+            .collect()
+    }
+
+    /// Run the `minimizers` or `sketch` subcommand, with the arguments after
+    /// it.
+    pub fn main<I: Iterator<Item = String>>(command: &str, args: I) -> io::Result<()> {
+        let mut args = args.peekable();
+        let canonical = args.peek().map_or(false, |arg| arg == "--canonical");
+        if canonical {
+            args.next();
+        }
+        let frame = parse(args.next());
+        assert!(frame <= MAX_FRAME, "k-mers are at most {} long", MAX_FRAME);
+        let n = parse(args.next());
+        let ids: Vec<_> = args.collect();
+        match command {
+            "minimizers" if ids.len() == 1 => {
+                let seq = read_records(&ids)?.remove(0);
+                with_word!(frame, W => minimizers::<W>(&seq, frame, n, canonical));
+            }
+            "sketch" if ids.len() == 2 => {
+                let seqs = read_records(&ids)?;
+                let (a, b) = with_word!(frame, W => (sketch::<W>(&seqs[0], frame, n, canonical),
+                                                     sketch::<W>(&seqs[1], frame, n, canonical)));
+                let (shared, size) = shared(&a, &b, n);
+                let jaccard = if size > 0 { shared as f64 / size as f64 } else { 0. };
+                let distance = if jaccard > 0. {
+                    // Not -0 for identical sketches.
+                    ((2. * jaccard / (1. + jaccard)).ln() / -(frame as f64)).max(0.)
+                } else {
+                    1.
+                };
+                println!("{}/{}\tshared hashes", shared, size);
+                println!("{:.6}\tJaccard index", jaccard);
+                println!("{:.6}\tMash distance", distance);
+            }
+            _ => panic!("{}", USAGE),
+        }
+        Ok(())
+    }
+}
+
+/// Report an error, and exit.
+fn fail<T>(e: io::Error) -> T {
+    eprintln!("{}", e);
+    std::process::exit(1)
 }
 
 fn main() {
+    match std::env::args().nth(1) {
+        Some(ref command) if command == "db" => {
+            db::main(std::env::args().skip(2)).unwrap_or_else(fail);
+            return;
+        }
+        Some(ref command) if command == "minimizers" || command == "sketch" => {
+            sketch::main(command, std::env::args().skip(2)).unwrap_or_else(fail);
+            return;
+        }
+        _ => (),
+    }
+    let mut record = "THREE".to_string();
+    let mut canonical = false;
+    let mut report_skipped = false;
+    let (mut spill, mut buckets) = (None, 64);
+    let (mut frames, mut queries, mut exports) = (vec![], vec![], vec![]);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--canonical" => canonical = true,
+            "--skipped" => report_skipped = true,
+            "--spill" => spill = Some(PathBuf::from(args.next().expect("--spill expects a directory"))),
+            "--buckets" => {
+                buckets = args.next().and_then(|s| s.parse().ok()).filter(|&n| n > 0)
+                    .expect("--buckets expects a positive number");
+            }
+            "--record" => record = args.next().expect("--record expects a name"),
+            "--frames" => frames.extend(parse_frames(&args.next().expect("--frames expects a list"))),
+            "--query" => {
+                let list = args.next().expect("--query expects a list of k-mers");
+                queries.extend(list.split(',').map(parse_query));
+            }
+            "--export" => {
+                let export = args.next().expect("--export expects K:FILE");
+                let i = export.find(':').expect("--export expects K:FILE");
+                match parse_frames(&export[..i])[..] {
+                    [frame] => exports.push(Export(frame, PathBuf::from(&export[i + 1..]))),
+                    _ => panic!("--export expects a single k-mer length"),
+                }
+            }
+            _ => panic!("unknown argument {}", arg),
+        }
+    }
+    if frames.is_empty() && queries.is_empty() && exports.is_empty() {
+        frames.extend(&FRAMES);
+        queries.extend(QUERIES.iter().map(|s| s.to_string()));
+    }
+    let items: Vec<_> = frames.into_iter().map(Freq)
+        .chain(queries.into_iter().map(Occ))
+        .chain(exports)
+        .collect();
+
     let stdin = std::io::stdin();
-    let input = get_seq(stdin.lock(), b">THREE");
-    let input = Arc::new(input);
-    let pool = CpuPool::new_num_cpus();
-
-    // In reverse to spawn big tasks first
-    let items: Vec<_> = ITEMS.iter().rev().map(|&item| {
-        let input = input.clone();
-        let future_freq = pool.spawn_fn(move || Ok::<_, ()>(item.gen_freq(&input)));
-        (item, future_freq)
-    }).collect();
-
-    for (item, future_freq) in items.into_iter().rev() {
-        item.print(&future_freq.wait().unwrap());
+    let mut reader = fasta_reader::Reader::new(stdin.lock());
+    if reader.find_header(record.as_bytes()).unwrap().is_none() {
+        panic!("no {} record in the input", record);
+    }
+
+    let report = |item: &Item, skipped| if report_skipped {
+        eprintln!("{}\t{}-mers skipped", skipped, item.frame());
+    };
+    if let Some(dir) = spill {
+        // The results are written as they are counted, not to hold them.
+        let spill = Spill::new(&mut reader, dir, buckets).unwrap();
+        let stdout = io::stdout();
+        let mut out = BufWriter::new(stdout.lock());
+        for item in &items {
+            let skipped = item.run_spilled(&spill, canonical, &mut out).unwrap_or_else(fail);
+            out.flush().unwrap();
+            report(item, skipped);
+        }
+    } else {
+        let mut input = Vec::with_capacity(65536);
+        reader.read_seq(&mut input, usize::max_value()).unwrap();
+        for c in &mut input {
+            *c = encode(*c);
+        }
+
+        // One thread per core takes the items in turn, big tasks first: the
+        // longer the k-mers, the more of them.
+        let mut order: Vec<_> = (0..items.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(items[i].frame()));
+        let next = AtomicUsize::new(0);
+        let results: Vec<_> = items.iter().map(|_| Mutex::new(None)).collect();
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        thread::scope(|s| {
+            for _ in 0..min(threads, items.len()) {
+                s.spawn(|| {
+                    while let Some(&i) = order.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        *results[i].lock().unwrap() = Some(items[i].run(&input, canonical));
+                    }
+                });
+            }
+        });
+        for (item, res) in items.iter().zip(results) {
+            let (res, skipped) = res.into_inner().unwrap().unwrap().unwrap_or_else(fail);
+            print!("{}", res);
+            report(item, skipped);
+        }
     }
 }
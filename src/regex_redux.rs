@@ -14,14 +14,21 @@ use std::fs;
 use std::sync::Arc;
 use std::thread;
 
+#[path = "common/fasta_reader.rs"]
+mod fasta_reader;
+
 macro_rules! regex { ($re:expr) => { ::regex::bytes::Regex::new($re).unwrap() } }
 
 fn main() {
-    let mut seq = fs::read("/dev/stdin").unwrap();
-    let ilen = seq.len();
+    let input = fs::read("/dev/stdin").unwrap();
+    let ilen = input.len();
 
     // Remove headers and newlines.
-    seq = regex!(">[^\n]*\n|\n").replace_all(&seq, &b""[..]).into_owned();
+    let mut seq = Vec::with_capacity(ilen);
+    for record in fasta_reader::Reader::new(&input[..]) {
+        seq.extend_from_slice(&record.unwrap().seq);
+    }
+    drop(input);
     let clen = seq.len();
 
     // Search for occurrences of the following patterns:
@@ -8,14 +8,21 @@
 
 extern crate rayon;
 
+#[path = "common/fasta_reader.rs"]
+mod fasta_reader;
+
+use fasta_reader::Reader;
 use std::cmp::min;
-use std::io::{BufRead, Result, Write, stdin, stdout};
+use std::io::{BufRead, BufReader, Result, Write, stdin, stdout};
 use std::mem::replace;
 
 fn main() -> Result<()> {
     let table = build_table();
-    for seq in get_sequences(&table)?.iter().rev() {
-        stdout().write_all(seq)?;
+    let mut reader = Reader::new(BufReader::with_capacity(64 * 1024, stdin()));
+    let stdout = stdout();
+    let mut stdout = stdout.lock();
+    for seq in get_sequences(&mut reader, &table)?.iter().rev() {
+        stdout.write_all(seq)?;
     }
     Ok(())
 }
@@ -47,47 +54,44 @@ fn build_table() -> [u8; 256] {
     table
 }
 
-/// Read each sequence from stdin, process it, and return it.
-fn get_sequences(table: &[u8; 256]) -> Result<Vec<Vec<u8>>> {
-    let stdin = stdin();
-    let mut input = stdin.lock();
+/// Read each record, process it, and return it formatted for output, last
+/// record first.
+fn get_sequences<R: BufRead + Send>(reader: &mut Reader<R>, table: &[u8; 256])
+                                    -> Result<Vec<Vec<u8>>> {
+    let header = match reader.next_header()? {
+        Some(header) => header,
+        None => return Ok(vec![]),
+    };
     let mut buf = Vec::with_capacity(16 * 1024);
-
-    // Read the header line.
-    input.read_until(b'\n', &mut buf)?;
+    buf.push(b'>');
+    buf.extend_from_slice(&header);
+    buf.push(b'\n');
     let start = buf.len();
 
-    // Read sequence data.
-    input.read_until(b'>', &mut buf)?;
-    let end = buf.len();
-    drop(input);
-
-    if buf[end - 1] == b'>' {
-        // Found the start of a new sequence. Process this one
-        // and start reading the next one in parallel.
-        let mut results = rayon::join(
-            || reverse_complement(&mut buf[start..end - 1], &table),
-            || get_sequences(table)).1?;
-        results.push(buf);
-        Ok(results)
-    } else {
-        // Reached the end of the file.
-        reverse_complement(&mut buf[start..end], &table);
-        Ok(vec![buf])
-    }
+    // Read sequence data, as laid out in the input.
+    let line_len = reader.read_seq_lines(&mut buf)? + 1;
+
+    // Process this record and start reading the next one in parallel.
+    let mut results = rayon::join(
+        || reverse_complement(&mut buf[start..], line_len, table),
+        || get_sequences(reader, table)).1?;
+    results.push(buf);
+    Ok(results)
 }
 
-/// Compute the reverse complement of one sequence.
-fn reverse_complement(seq: &mut [u8], table: &[u8; 256]) {
+/// Compute the reverse complement of one sequence, whose lines are
+/// `line_len` bytes long including the terminating \n.
+fn reverse_complement(seq: &mut [u8], line_len: usize, table: &[u8; 256]) {
+    if seq.is_empty() {
+        return;
+    }
     let len = seq.len() - 1;
     let seq = &mut seq[..len]; // Drop the last newline
-    let trailing_len = len % LINE_LEN;
+    let trailing_len = len % line_len;
     let (left, right) = seq.split_at_mut(len / 2);
-    reverse_complement_left_right(left, right, trailing_len, table);
+    reverse_complement_left_right(left, right, line_len, trailing_len, table);
 }
 
-/// Length of a normal line including the terminating \n.
-const LINE_LEN: usize = 61;
 /// Maximum number of bytes to process in serial.
 const SEQUENTIAL_SIZE: usize = 16 * 1024;
 
@@ -98,6 +102,7 @@ const SEQUENTIAL_SIZE: usize = 16 * 1024;
 /// will have equal lengths.
 fn reverse_complement_left_right(mut left: &mut [u8],
                                  mut right: &mut [u8],
+                                 line_len: usize,
                                  trailing_len: usize,
                                  table: &[u8; 256]) {
     let len = left.len();
@@ -120,7 +125,7 @@ fn reverse_complement_left_right(mut left: &mut [u8],
             reverse_chunks(a, b, table);
 
             // Get the chunk up to the newline in `left`.
-            let n = LINE_LEN - 1 - trailing_len;
+            let n = line_len - 1 - trailing_len;
             a = left.split_off_left(n);
             b = right.split_off_right(n);
             left.split_off_left(1); // Skip the newline in `left`.
@@ -136,13 +141,13 @@ fn reverse_complement_left_right(mut left: &mut [u8],
         }
     } else {
         // Divide large chunks in half and fork them into two parallel tasks.
-        let line_count = len / LINE_LEN;
-        let mid = line_count / 2 * LINE_LEN; // Split on a whole number of lines.
+        let line_count = len / line_len;
+        let mid = line_count / 2 * line_len; // Split on a whole number of lines.
 
         let left1 = left.split_off_left(mid);
         let right1 = right.split_off_right(mid);
-        rayon::join(|| reverse_complement_left_right(left,  right,  trailing_len, table),
-                    || reverse_complement_left_right(left1, right1, trailing_len, table));
+        rayon::join(|| reverse_complement_left_right(left,  right,  line_len, trailing_len, table),
+                    || reverse_complement_left_right(left1, right1, line_len, trailing_len, table));
     }
 }
 
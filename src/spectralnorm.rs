@@ -9,6 +9,9 @@
 
 extern crate rayon;
 use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::iter::once;
 use std::ops::*;
 
 #[derive(Clone, Copy)]
@@ -34,6 +37,12 @@ impl Add for F64x2 {
         F64x2(self.0 + rhs.0, self.1 + rhs.1)
     }
 }
+impl Sub for F64x2 {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        F64x2(self.0 - rhs.0, self.1 - rhs.1)
+    }
+}
 impl Mul for F64x2 {
     type Output = Self;
     fn mul(self, rhs: Self) -> Self {
@@ -47,59 +56,562 @@ impl Div for F64x2 {
     }
 }
 
+/// How long to run the power iteration for.
+#[derive(Clone, Copy)]
+enum Iterations {
+    /// Run exactly this many iterations (the benchmark uses 10).
+    Fixed(usize),
+    /// Iterate until two successive estimates differ by at most `tolerance`
+    /// relative to the estimate, giving up after `max` iterations.
+    Converge { tolerance: f64, max: usize },
+}
+
 fn main() {
-    let n = std::env::args().nth(1)
-        .and_then(|n| n.parse().ok())
-        .unwrap_or(100);
-    let answer = spectralnorm(n);
-    println!("{:.9}", answer);
+    let mut n = 100;
+    let (mut mtx, mut dense, mut blocked) = (None, false, true);
+    let (mut count, mut tolerance, mut max) = (None, None, None);
+    let (mut trace, mut vector, mut second, mut csv) = (false, false, false, false);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next()
+            .unwrap_or_else(|| panic!("{} expects an argument", arg));
+        match &*arg {
+            "--iterations" => count = Some(value().parse().unwrap()),
+            "--tolerance" => tolerance = Some(value().parse().unwrap()),
+            "--max-iterations" => max = Some(value().parse().unwrap()),
+            "--mtx" => mtx = Some(value()),
+            "--dense" => dense = true,
+            "--simple-kernel" => blocked = false,
+            "--trace" => trace = true,
+            "--vector" => vector = true,
+            "--second" => second = true,
+            "--csv" => csv = true,
+            _ => n = arg.parse().unwrap_or_else(|_| panic!("unknown argument {}", arg)),
+        }
+    }
+    let iterations = match (tolerance, max) {
+        (None, None) => Iterations::Fixed(count.unwrap_or(10)),
+        _ if count.is_some() => {
+            panic!("--iterations can't be combined with --tolerance or --max-iterations")
+        }
+        (tolerance, max) => Iterations::Converge {
+            tolerance: tolerance.unwrap_or(1e-12),
+            max: max.unwrap_or(1000),
+        },
+    };
+
+    let matrix: Box<dyn Matrix> = match mtx {
+        Some(path) => {
+            let file = BufReader::new(File::open(path).unwrap());
+            read_matrix_market(file, dense).unwrap()
+        }
+        None => Box::new(Implicit { n: n, a: a, blocked: blocked }),
+    };
+    let first = spectralnorm(&*matrix, iterations, None);
+    let second = if second {
+        Some(spectralnorm(&*matrix, iterations, Some(&first.vector)).value)
+    } else {
+        None
+    };
+
+    if csv {
+        // A single table in long format, with only the requested quantities.
+        println!("quantity,index,value");
+        println!("norm,,{:e}", first.value);
+        if let Some(second) = second {
+            println!("second,,{:e}", second);
+        }
+        for (i, &(estimate, residual)) in first.trace.iter().enumerate().filter(|_| trace) {
+            println!("estimate,{},{:e}", i + 1, estimate);
+            println!("residual,{},{:e}", i + 1, residual);
+        }
+        for (i, x) in unpack(&first.vector, matrix.cols()).enumerate().filter(|_| vector) {
+            println!("vector,{},{:e}", i, x);
+        }
+    } else {
+        println!("{:.9}", first.value);
+        if let Some(second) = second {
+            println!("second {:.9}", second);
+        }
+        for (i, &(estimate, residual)) in first.trace.iter().enumerate().filter(|_| trace) {
+            println!("iteration {} {:.9} {:e}", i + 1, estimate, residual);
+        }
+        for x in unpack(&first.vector, matrix.cols()).filter(|_| vector) {
+            println!("{:.9}", x);
+        }
+    }
+}
+
+/// The outcome of `spectralnorm`.
+struct Estimate {
+    /// The estimated singular value.
+    value: f64,
+    /// The matching right singular vector, normalised.
+    vector: Vec<F64x2>,
+    /// The estimate after each iteration, with the residual |A^T A v - s^2 v|
+    /// for the normalised vector v.
+    trace: Vec<(f64, f64)>,
+}
+
+/// Estimate the spectral norm (largest singular value) of a matrix with the
+/// power method on A^T A. If `deflate` is a normalised right singular vector,
+/// the iteration is kept orthogonal to it, estimating the next singular value.
+fn spectralnorm<M>(m: &M, iterations: Iterations, deflate: Option<&[F64x2]>) -> Estimate
+                   where M: Matrix + ?Sized {
+    // Group all vectors in pairs of two for SIMD convenience, padding with a
+    // zero when the length is odd.
+    let (rows, cols) = ((m.rows() + 1) / 2, (m.cols() + 1) / 2);
+    let mut u = vec![F64x2::splat(0.0); cols];
+    let mut v = vec![F64x2::splat(0.0); cols];
+    let mut tmp = vec![F64x2::splat(0.0); rows];
+    let project = |v: &mut [F64x2]| if let Some(w) = deflate {
+        let c = F64x2::splat(dot(v, w));
+        for (x, &y) in v.iter_mut().zip(w) {
+            *x = *x - c * y;
+        }
+    };
+    // Scale a vector to unit length, returning false if it is zero. Without
+    // this each iteration would scale the vectors by s^4, which overflows or
+    // underflows for explicit matrices.
+    let normalise = |v: &mut [F64x2]| {
+        let norm = dot(v, v).sqrt();
+        if norm == 0.0 {
+            return false;
+        }
+        let norm = F64x2::splat(norm);
+        for x in v.iter_mut() {
+            *x = *x / norm;
+        }
+        true
+    };
+
+    let (count, tolerance) = match iterations {
+        Iterations::Fixed(count) => (count, None),
+        Iterations::Converge { tolerance, max } => (max, Some(tolerance)),
+    };
+    let mut trace = Vec::new();
+    let mut value = 0.0;
+
+    // Start from all ones, or when deflating from pseudo-random vectors
+    // until one isn't parallel to `deflate`: all ones is the top singular
+    // vector of any matrix with constant row and column sums.
+    let mut seed = if deflate.is_some() { 1 } else { 0 };
+    loop {
+        start_vector(&mut u, m.cols(), seed);
+        let norm = dot(&u, &u).sqrt();
+        project(&mut u);
+        if dot(&u, &u).sqrt() > 1e-6 * norm {
+            break;
+        }
+        if seed == MAX_STARTS {
+            // Nothing is left once `deflate` is removed: a single column.
+            for x in u.iter_mut() {
+                *x = F64x2::splat(0.0);
+            }
+            break;
+        }
+        seed += 1;
+    }
+    normalise(&mut u);
+    for _ in 0..count {
+        mult_at_av(m, &u, &mut v, &mut tmp);
+        project(&mut v);
+        if !normalise(&mut v) {
+            // A^T A u = 0: the (remaining) singular values are all zero.
+            value = 0.0;
+            trace.push((0.0, 0.0));
+            break;
+        }
+        mult_at_av(m, &v, &mut u, &mut tmp);
+        project(&mut u);
+
+        // Now u = A^T A v with v normalised, so u.v is the Rayleigh quotient.
+        let current = dot(&u, &v).max(0.0).sqrt();
+        let lambda = F64x2::splat(current * current);
+        let residual = u.iter().zip(&v)
+            .map(|(&x, &y)| x - lambda * y)
+            .fold(F64x2::splat(0.0), |s, r| s + r * r)
+            .sum();
+        trace.push((current, residual.sqrt()));
+        value = current;
+        if !normalise(&mut u) {
+            break;
+        }
+        if let Some(tolerance) = tolerance {
+            let previous = trace.iter().rev().nth(1).map_or(std::f64::INFINITY, |x| x.0);
+            if (current - previous).abs() <= tolerance * current {
+                break;
+            }
+        }
+    }
+
+    Estimate {
+        value: value,
+        vector: u,
+        trace: trace,
+    }
 }
 
-fn spectralnorm(n: usize) -> f64 {
-    // Group all vectors in pairs of two for SIMD convenience.
-    assert!(n % 2 == 0, "only even lengths are accepted");
-    let mut u = vec![F64x2::splat(1.0); n / 2];
-    let mut v = vec![F64x2::splat(0.0); n / 2];
-    let mut tmp = vec![F64x2::splat(0.0); n / 2];
+/// Number of pseudo-random start vectors tried by `spectralnorm`.
+const MAX_STARTS: u64 = 8;
 
-    for _ in 0..10 {
-        mult_at_av(&u, &mut v, &mut tmp);
-        mult_at_av(&v, &mut u, &mut tmp);
+/// Fill `u` with the start vector `seed` for a matrix with `n` columns: all
+/// ones for 0, else pseudo-random entries in [-1, 1), from xorshift64*.
+fn start_vector(u: &mut [F64x2], n: usize, seed: u64) {
+    let mut state = seed.wrapping_mul(0x9e3779b97f4a7c15) | 1;
+    let mut next = || if seed == 0 {
+        1.0
+    } else {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        (state.wrapping_mul(0x2545f4914f6cdd1d) >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    };
+    for x in u.iter_mut() {
+        *x = F64x2::new(next(), next());
+    }
+    if n % 2 == 1 {
+        u[n / 2].1 = 0.0;
     }
+}
+
+fn mult_at_av<M: Matrix + ?Sized>(m: &M, v: &[F64x2], out: &mut [F64x2], tmp: &mut [F64x2]) {
+    m.mult(v, tmp);
+    m.mult_transposed(tmp, out);
+}
 
-    (dot(&u, &v) / dot(&v, &v)).sqrt()
+/// A matrix usable by `spectralnorm`. Vectors are grouped in pairs, and the
+/// padding slot of an odd length vector must be left at zero.
+trait Matrix: Sync {
+    fn rows(&self) -> usize;
+    fn cols(&self) -> usize;
+    /// Compute `out = A v`.
+    fn mult(&self, v: &[F64x2], out: &mut [F64x2]);
+    /// Compute `out = A^T v`.
+    fn mult_transposed(&self, v: &[F64x2], out: &mut [F64x2]);
 }
 
-fn mult_at_av(v: &[F64x2], out: &mut [F64x2], tmp: &mut [F64x2]) {
-    mult(v, tmp, a);
-    mult(tmp, out, |i, j| a(j, i));
+/// A square matrix whose entries are computed on the fly by `a(i, j)`, which
+/// returns the reciprocal 1 / A(i, j) of each entry: the kernel divides by it,
+/// so zero entries are simply `f64::INFINITY`.
+///
+/// When `n` is odd the vectors are padded to an even length, so `a` may also
+/// be called with `i` or `j` equal to `n`; it must not return zero there.
+///
+/// The reciprocals are not exact, so unlike the explicit matrices this one
+/// can't use fused multiply-add without changing the result. `blocked` selects
+/// `mult_blocked` rather than the simpler `mult` kernel.
+struct Implicit<A> {
+    n: usize,
+    a: A,
+    blocked: bool,
+}
+impl<A> Matrix for Implicit<A> where A: Fn(usize, usize) -> f64 + Sync {
+    fn rows(&self) -> usize { self.n }
+    fn cols(&self) -> usize { self.n }
+    fn mult(&self, v: &[F64x2], out: &mut [F64x2]) {
+        if self.blocked {
+            mult_blocked(self.n, v, out, &self.a);
+        } else {
+            mult(self.n, v, out, &self.a);
+        }
+    }
+    fn mult_transposed(&self, v: &[F64x2], out: &mut [F64x2]) {
+        let a = &self.a;
+        if self.blocked {
+            mult_blocked(self.n, v, out, |i, j| a(j, i));
+        } else {
+            mult(self.n, v, out, |i, j| a(j, i));
+        }
+    }
 }
 
-fn mult<F>(v: &[F64x2], out: &mut [F64x2], a: F)
-           where F: Fn([usize; 2], [usize; 2]) -> F64x2 + Sync {
+fn mult<F>(n: usize, v: &[F64x2], out: &mut [F64x2], a: F)
+           where F: Fn(usize, usize) -> f64 + Sync {
     // Parallelize along the output vector, with each pair of slots as a parallelism unit.
     out.par_iter_mut().enumerate().for_each(|(i, slot)| {
         // We're computing everything in chunks of two so the indces of slot[0] and slot[1] are 2*i
         // and 2*i + 1.
         let i = 2 * i;
-        let (i0, i1) = ([i; 2], [i + 1; 2]);
 
         // Each slot in the pair gets its own sum, which is further computed in two f64 lanes (which
         // are summed at the end.
         let (mut sum0, mut sum1) = (F64x2::splat(0.0), F64x2::splat(0.0));
         for (j, x) in v.iter().enumerate() {
-            let j = [2 * j, 2 * j  + 1];
-            div_and_add(*x, a(i0, j), a(i1, j), &mut sum0, &mut sum1);
+            let j = 2 * j;
+            div_and_add(*x,
+                        F64x2::new(a(i, j), a(i, j + 1)),
+                        F64x2::new(a(i + 1, j), a(i + 1, j + 1)),
+                        &mut sum0, &mut sum1);
+        }
+
+        // Sum the two lanes for each slot, keeping the padding slot at zero.
+        *slot = F64x2::new(sum0.sum(), if i + 1 < n { sum1.sum() } else { 0.0 });
+    });
+}
+
+/// Number of output pairs computed together by one task of `mult_blocked`.
+const ROW_BLOCK: usize = 8;
+/// Number of input pairs in a column tile of `mult_blocked`: 2 KiB of `v`,
+/// which stays in L1 while every row of the block walks over it.
+const COL_TILE: usize = 128;
+
+/// Same as `mult`, but tiled so that each parallel task reuses a tile of `v`
+/// from L1 for `ROW_BLOCK` pairs of rows. Every row still sums its terms in
+/// the same order, so the result is identical to `mult`.
+fn mult_blocked<F>(n: usize, v: &[F64x2], out: &mut [F64x2], a: F)
+                   where F: Fn(usize, usize) -> f64 + Sync {
+    out.par_chunks_mut(ROW_BLOCK).enumerate().for_each(|(block, out)| {
+        let first = 2 * block * ROW_BLOCK;
+        let mut sums = [(F64x2::splat(0.0), F64x2::splat(0.0)); ROW_BLOCK];
+        for (tile, v) in v.chunks(COL_TILE).enumerate() {
+            let first_j = 2 * tile * COL_TILE;
+            for (k, sum) in sums[..out.len()].iter_mut().enumerate() {
+                let i = first + 2 * k;
+                for (j, &x) in v.iter().enumerate() {
+                    let j = first_j + 2 * j;
+                    sum.0 = sum.0 + x / F64x2::new(a(i, j), a(i, j + 1));
+                    sum.1 = sum.1 + x / F64x2::new(a(i + 1, j), a(i + 1, j + 1));
+                }
+            }
         }
 
-        // Sum the two lanes for each slot.
-        *slot = F64x2::new(sum0.sum(), sum1.sum());
+        for (k, (slot, &(sum0, sum1))) in out.iter_mut().zip(&sums).enumerate() {
+            let i = first + 2 * k;
+            *slot = F64x2::new(sum0.sum(), if i + 1 < n { sum1.sum() } else { 0.0 });
+        }
     });
 }
 
-fn a(i: [usize; 2], j: [usize; 2]) -> F64x2 {
-   F64x2::new(((i[0] + j[0]) * (i[0] + j[0] + 1) / 2 + i[0] + 1) as f64,
-    ((i[1] + j[1]) * (i[1] + j[1] + 1) / 2 + i[1] + 1) as f64)
+/// The benchmark matrix, A(i, j) = 1 / ((i + j) * (i + j + 1) / 2 + i + 1), as
+/// expected by `Implicit`.
+fn a(i: usize, j: usize) -> f64 {
+    ((i + j) * (i + j + 1) / 2 + i + 1) as f64
+}
+
+/// Iterate over the first `len` elements of a vector grouped in pairs.
+fn unpack<'a>(v: &'a [F64x2], len: usize) -> impl Iterator<Item = f64> + 'a {
+    v.iter().flat_map(|x| once(x.0).chain(once(x.1))).take(len)
+}
+
+/// Get the element `j` of a vector grouped in pairs.
+fn lane(v: &[F64x2], j: usize) -> f64 {
+    if j % 2 == 0 { v[j / 2].0 } else { v[j / 2].1 }
+}
+
+/// Compute `acc + x * y`, with a single rounding if `fma` is set.
+#[inline(always)]
+fn mul_add(fma: bool, x: f64, y: f64, acc: f64) -> f64 {
+    if fma { x.mul_add(y, acc) } else { acc + x * y }
+}
+
+/// Call `f(fma)`, where `fma` tells whether the CPU has fused multiply-add.
+/// In that case `f` is compiled with the feature enabled, so that `mul_add`
+/// is a single instruction rather than a call to the (slow) software version.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn with_fma<R, F: FnOnce(bool) -> R>(f: F) -> R {
+    #[target_feature(enable = "fma")]
+    unsafe fn fma<R, F: FnOnce(bool) -> R>(f: F) -> R { f(true) }
+
+    if is_x86_feature_detected!("fma") {
+        unsafe { fma(f) }
+    } else {
+        f(false)
+    }
+}
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn with_fma<R, F: FnOnce(bool) -> R>(f: F) -> R {
+    f(false)
+}
+
+/// Fill `out` in parallel with `row(i, fma)` for each valid index `i < len`,
+/// see `with_fma`.
+fn fill<F>(len: usize, out: &mut [F64x2], row: F) where F: Fn(usize, bool) -> f64 + Sync {
+    out.par_chunks_mut(ROW_BLOCK).enumerate().for_each(|(block, out)| with_fma(|fma| {
+        for (k, slot) in out.iter_mut().enumerate() {
+            let i = 2 * (block * ROW_BLOCK + k);
+            *slot = F64x2::new(row(i, fma), if i + 1 < len { row(i + 1, fma) } else { 0.0 });
+        }
+    }));
+}
+
+/// An explicit matrix stored in row major order.
+struct Dense {
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+}
+impl Dense {
+    fn from_entries(rows: usize, cols: usize, entries: &[(usize, usize, f64)]) -> Dense {
+        let mut data = vec![0.0; rows * cols];
+        for &(i, j, x) in entries {
+            data[i * cols + j] += x;
+        }
+        Dense { rows: rows, cols: cols, data: data }
+    }
+}
+impl Matrix for Dense {
+    fn rows(&self) -> usize { self.rows }
+    fn cols(&self) -> usize { self.cols }
+    fn mult(&self, v: &[F64x2], out: &mut [F64x2]) {
+        fill(self.rows, out, |i, fma| {
+            let row = &self.data[i * self.cols..(i + 1) * self.cols];
+            row.iter().enumerate().fold(0.0, |acc, (j, &x)| mul_add(fma, x, lane(v, j), acc))
+        });
+    }
+    fn mult_transposed(&self, v: &[F64x2], out: &mut [F64x2]) {
+        fill(self.cols, out, |j, fma| {
+            let col = self.data[j..].iter().step_by(self.cols);
+            col.enumerate().fold(0.0, |acc, (i, &x)| mul_add(fma, x, lane(v, i), acc))
+        });
+    }
+}
+
+/// An explicit matrix in compressed sparse row format: the entries of row `i`
+/// are at `start[i]..start[i + 1]` in `col` and `value`.
+struct Csr {
+    rows: usize,
+    start: Vec<usize>,
+    col: Vec<usize>,
+    value: Vec<f64>,
+}
+impl Csr {
+    fn from_entries(rows: usize, entries: &mut Vec<(usize, usize, f64)>) -> Csr {
+        entries.sort_by_key(|&(i, j, _)| (i, j));
+        let mut start = vec![0; rows + 1];
+        for &(i, _, _) in entries.iter() {
+            start[i + 1] += 1;
+        }
+        for i in 0..rows {
+            start[i + 1] += start[i];
+        }
+        Csr {
+            rows: rows,
+            start: start,
+            col: entries.iter().map(|&(_, j, _)| j).collect(),
+            value: entries.iter().map(|&(_, _, x)| x).collect(),
+        }
+    }
+    fn mult(&self, v: &[F64x2], out: &mut [F64x2]) {
+        fill(self.rows, out, |i, fma| {
+            let range = self.start[i]..self.start[i + 1];
+            self.col[range.clone()].iter()
+                .zip(&self.value[range])
+                .fold(0.0, |acc, (&j, &x)| mul_add(fma, x, lane(v, j), acc))
+        });
+    }
+}
+
+/// A sparse matrix, stored along with its transpose so both products are
+/// parallel over the rows.
+struct Sparse {
+    a: Csr,
+    at: Csr,
+}
+impl Sparse {
+    fn from_entries(rows: usize, cols: usize, mut entries: Vec<(usize, usize, f64)>) -> Sparse {
+        let a = Csr::from_entries(rows, &mut entries);
+        for e in &mut entries {
+            *e = (e.1, e.0, e.2);
+        }
+        Sparse { a: a, at: Csr::from_entries(cols, &mut entries) }
+    }
+}
+impl Matrix for Sparse {
+    fn rows(&self) -> usize { self.a.rows }
+    fn cols(&self) -> usize { self.at.rows }
+    fn mult(&self, v: &[F64x2], out: &mut [F64x2]) {
+        self.a.mult(v, out);
+    }
+    fn mult_transposed(&self, v: &[F64x2], out: &mut [F64x2]) {
+        self.at.mult(v, out);
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read a real, integer or pattern matrix in Matrix Market format. `array`
+/// files give a `Dense` matrix and `coordinate` files a `Sparse` one, unless
+/// `dense` is set.
+fn read_matrix_market<R: BufRead>(r: R, dense: bool) -> io::Result<Box<dyn Matrix>> {
+    let mut lines = r.lines();
+    let header = lines.next().unwrap_or_else(|| Ok(String::new()))?.to_lowercase();
+    let header: Vec<_> = header.split_whitespace().collect();
+    if header.len() != 5 || header[0] != "%%matrixmarket" || header[1] != "matrix" {
+        return Err(invalid_data(format!("not a Matrix Market matrix: {:?}", header)));
+    }
+    let (array, field, symmetry) = (header[2] == "array", header[3], header[4]);
+    if !array && header[2] != "coordinate" {
+        return Err(invalid_data(format!("unsupported format {}", header[2])));
+    }
+    if !["real", "double", "integer", "pattern"].contains(&field) || array && field == "pattern" {
+        return Err(invalid_data(format!("unsupported field {}", field)));
+    }
+    if !["general", "symmetric", "skew-symmetric"].contains(&symmetry) {
+        return Err(invalid_data(format!("unsupported symmetry {}", symmetry)));
+    }
+
+    // The size line and the entries, skipping comments and blank lines.
+    let mut numbers = Vec::new();
+    for line in lines {
+        let line = line?;
+        if line.starts_with('%') {
+            continue;
+        }
+        for word in line.split_whitespace() {
+            numbers.push(word.parse::<f64>()
+                .map_err(|_| invalid_data(format!("invalid number {}", word)))?);
+        }
+    }
+    let size_len = if array { 2 } else { 3 };
+    if numbers.len() < size_len {
+        return Err(invalid_data("missing size line".to_string()));
+    }
+    let (size, numbers) = numbers.split_at(size_len);
+    let (rows, cols) = (size[0] as usize, size[1] as usize);
+
+    // Collect (row, column, value) triplets, 0 based.
+    let mut entries = Vec::new();
+    if array {
+        // Column major, only the lower triangle for symmetric matrices.
+        let mut values = numbers.iter();
+        for j in 0..cols {
+            let first = match symmetry { "general" => 0, "symmetric" => j, _ => j + 1 };
+            for i in first..rows {
+                let x = *values.next()
+                    .ok_or_else(|| invalid_data("missing entries".to_string()))?;
+                entries.push((i, j, x));
+            }
+        }
+    } else {
+        let (nnz, width) = (size[2] as usize, if field == "pattern" { 2 } else { 3 });
+        if numbers.len() != width * nnz {
+            return Err(invalid_data(format!("expected {} entries", nnz)));
+        }
+        for e in numbers.chunks(width) {
+            let (i, j) = (e[0] as usize, e[1] as usize);
+            if i == 0 || i > rows || j == 0 || j > cols {
+                return Err(invalid_data(format!("entry ({}, {}) out of bounds", i, j)));
+            }
+            entries.push((i - 1, j - 1, if width == 3 { e[2] } else { 1.0 }));
+        }
+    }
+    if symmetry != "general" {
+        let sign = if symmetry == "symmetric" { 1.0 } else { -1.0 };
+        let mirrored: Vec<_> = entries.iter()
+            .filter(|&&(i, j, _)| i != j)
+            .map(|&(i, j, x)| (j, i, sign * x))
+            .collect();
+        entries.extend(mirrored);
+    }
+
+    Ok(if array || dense {
+        Box::new(Dense::from_entries(rows, cols, &entries))
+    } else {
+        Box::new(Sparse::from_entries(rows, cols, entries))
+    })
 }
 
 fn dot(v: &[F64x2], u: &[F64x2]) -> f64 {